@@ -0,0 +1,271 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::builder::StyledStr;
+use clap::{value_parser, Arg, ArgAction};
+use rustyline::history::{DefaultHistory, History as _};
+use rustyline::{Editor, Helper};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::format::{self, OutputFormat};
+use crate::io::{self, output};
+use crate::session::{self, Session};
+use crate::History;
+
+/// The parts of the line editor used by the built-in commands.
+pub(crate) trait LineEditor {
+    fn history(&self) -> &DefaultHistory;
+    fn clear_history(&mut self) -> rustyline::Result<()>;
+    fn clear_screen(&mut self) -> rustyline::Result<()>;
+}
+
+impl<H: Helper> LineEditor for Editor<H, DefaultHistory> {
+    fn history(&self) -> &DefaultHistory {
+        Editor::history(self)
+    }
+
+    fn clear_history(&mut self) -> rustyline::Result<()> {
+        Editor::clear_history(self)
+    }
+
+    fn clear_screen(&mut self) -> rustyline::Result<()> {
+        Editor::clear_screen(self)
+    }
+}
+
+/// What the REPL should do after a built-in command.
+pub(crate) enum Flow {
+    Continue,
+    Exit,
+}
+
+/// Definitions of the REPL-only commands.
+fn commands() -> Vec<clap::Command> {
+    vec![
+        clap::Command::new("help")
+            .about("Print help of the given command")
+            .arg(Arg::new("command").num_args(0..)),
+        clap::Command::new("exit")
+            .alias("quit")
+            .about("Exit the REPL"),
+        clap::Command::new("history")
+            .about("Print the history")
+            .arg(
+                Arg::new("count")
+                    .value_parser(value_parser!(usize))
+                    .help("Only print the last <count> entries"),
+            )
+            .arg(
+                Arg::new("clear")
+                    .short('c')
+                    .long("clear")
+                    .action(ArgAction::SetTrue)
+                    .help("Clear the history"),
+            ),
+        clap::Command::new("clear").about("Clear the screen"),
+        clap::Command::new("source")
+            .about("Execute the commands in a file, one per line")
+            .arg(
+                Arg::new("file")
+                    .required(true)
+                    .value_parser(value_parser!(PathBuf)),
+            ),
+        clap::Command::new("set")
+            .about("Set variables, or print all of them")
+            .arg(
+                Arg::new("assignment")
+                    .value_name("NAME=VALUE")
+                    .num_args(0..),
+            ),
+        clap::Command::new("let")
+            .about("Set a variable, e.g. `let last = $?`")
+            .arg(
+                Arg::new("assignment")
+                    .value_name("NAME = VALUE")
+                    .required(true)
+                    .num_args(1..)
+                    .allow_hyphen_values(true),
+            ),
+        clap::Command::new("unset")
+            .about("Remove variables")
+            .arg(Arg::new("name").required(true).num_args(1..)),
+        clap::Command::new("alias")
+            .about("Define aliases, or print them")
+            .long_about(
+                "Define aliases, or print them.\n\n\
+                 An alias is a line executed in place of its name. It may refer to its \
+                 args with $1, $2... or all of them with $@, otherwise they are appended \
+                 to it.",
+            )
+            .arg(Arg::new("alias").value_name("NAME[=VALUE]").num_args(0..)),
+        clap::Command::new("unalias")
+            .about("Remove aliases")
+            .arg(Arg::new("name").required(true).num_args(1..)),
+        clap::Command::new("output")
+            .about("Set the default output format, or print it")
+            .arg(Arg::new("format").value_parser(value_parser!(OutputFormat))),
+    ]
+}
+
+/// Add the built-in commands to `root`, so they show up in help and
+/// completions, and return those that don't clash with its subcommands.
+pub(crate) fn inject(root: &mut clap::Command) -> Vec<clap::Command> {
+    let builtins: Vec<_> = commands()
+        .into_iter()
+        .filter(|c| {
+            std::iter::once(c.get_name())
+                .chain(c.get_all_aliases())
+                .all(|name| root.find_subcommand(name).is_none())
+        })
+        .collect();
+    *root = root
+        .clone()
+        .disable_help_subcommand(true)
+        .subcommands(builtins.clone());
+    builtins
+}
+
+/// Find the built-in command named `name` in `builtins`.
+pub(crate) fn find<'a>(builtins: &'a [clap::Command], name: &str) -> Option<&'a clap::Command> {
+    builtins
+        .iter()
+        .find(|c| c.get_name() == name || c.get_all_aliases().any(|a| a == name))
+}
+
+/// Run the built-in command `builtin` with `args` in `session`.
+///
+/// `editor` is `None` when running a script, where the commands using it
+/// are not available.
+pub(crate) fn run<Ctx>(
+    session: &Session<'_, '_, Ctx>,
+    builtin: &clap::Command,
+    args: &[String],
+    editor: Option<&mut dyn LineEditor>,
+) -> Result<Flow> {
+    let Some(m) = session::try_matches(builtin.clone(), args)? else {
+        return Ok(Flow::Continue);
+    };
+    let editor =
+        || editor.ok_or_else(|| anyhow!("`{}` is only available in the REPL", builtin.get_name()));
+    match builtin.get_name() {
+        "help" => {
+            let path: Vec<&str> = m
+                .get_many::<String>("command")
+                .map(|path| path.map(String::as_str).collect())
+                .unwrap_or_default();
+            let root = &session.scope_cmd().cmd;
+            io::print_styled(&render_help(root, &path)?, root.get_color())?;
+        }
+        "exit" => return Ok(Flow::Exit),
+        "history" => {
+            let editor = editor()?;
+            if m.get_flag("clear") {
+                editor.clear_history()?;
+                if let Some(path) = &*session.history_path() {
+                    History::clear_file(path)
+                        .with_context(|| format!("failed to remove `{}`", path.display()))?;
+                }
+            } else {
+                let history = editor.history();
+                let count = m
+                    .get_one::<usize>("count")
+                    .copied()
+                    .unwrap_or(history.len());
+                let skip = history.len().saturating_sub(count);
+                let mut output = output();
+                for (i, entry) in history.iter().enumerate().skip(skip) {
+                    writeln!(output, "{:>5}  {}", i + 1, entry)?;
+                }
+            }
+        }
+        "clear" => editor()?.clear_screen()?,
+        "source" => session.source(m.get_one::<PathBuf>("file").unwrap())?,
+        "set" => match m.get_many::<String>("assignment") {
+            Some(assignments) => {
+                for assignment in assignments {
+                    let (name, value) = assignment
+                        .split_once('=')
+                        .ok_or_else(|| anyhow!("expected NAME=VALUE, got `{}`", assignment))?;
+                    session.set_var(name, value.to_owned())?;
+                }
+            }
+            None => {
+                let mut output = output();
+                for (name, value) in session.vars().iter() {
+                    writeln!(output, "{}={}", name, shell_words::quote(value))?;
+                }
+            }
+        },
+        "let" => {
+            let words: Vec<&str> = m
+                .get_many::<String>("assignment")
+                .unwrap()
+                .map(String::as_str)
+                .collect();
+            let assignment = words.join(" ");
+            let (name, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| anyhow!("expected NAME = VALUE, got `{}`", assignment))?;
+            session.set_var(name.trim(), value.trim().to_owned())?;
+        }
+        "unset" => {
+            for name in m.get_many::<String>("name").unwrap() {
+                session.unset_var(name);
+            }
+        }
+        "alias" => {
+            let mut output = output();
+            let aliases: Vec<&String> = m
+                .get_many::<String>("alias")
+                .map(Iterator::collect)
+                .unwrap_or_default();
+            if aliases.is_empty() {
+                for (name, value) in session.aliases().iter() {
+                    writeln!(output, "alias {}={}", name, shell_words::quote(value))?;
+                }
+            }
+            for alias in aliases {
+                match alias.split_once('=') {
+                    Some((name, value)) => session.set_alias(name, value.to_owned())?,
+                    None => match session.aliases().get(alias.as_str()) {
+                        Some(value) => {
+                            writeln!(output, "alias {}={}", alias, shell_words::quote(value))?
+                        }
+                        None => bail!("no such alias: `{}`", alias),
+                    },
+                }
+            }
+        }
+        "unalias" => {
+            for name in m.get_many::<String>("name").unwrap() {
+                session.unset_alias(name)?;
+            }
+        }
+        "output" => match m.get_one::<OutputFormat>("format") {
+            Some(&format) => format::set_default_format(format),
+            None => writeln!(output(), "{}", format::default_format())?,
+        },
+        name => bail!("not a built-in command: `{}`", name),
+    }
+    Ok(Flow::Continue)
+}
+
+/// Render the help of the subcommand at `path` in `root`, with usage written
+/// relative to `root` instead of the binary name.
+pub(crate) fn render_help(root: &clap::Command, path: &[&str]) -> Result<StyledStr> {
+    let mut cmd = root;
+    let mut names = Vec::new();
+    for name in path {
+        match cmd.find_subcommand(name) {
+            Some(subcmd) => cmd = subcmd,
+            None => bail!("no such command: `{}`", path.join(" ")),
+        }
+        names.push(cmd.get_name());
+    }
+    // Without a path, it's the help of `root` itself, as with `-h`.
+    let bin_name = if names.is_empty() {
+        root.get_name().to_owned()
+    } else {
+        names.join(" ")
+    };
+    Ok(cmd.clone().bin_name(bin_name).render_help())
+}
@@ -0,0 +1,363 @@
+use clap::{Arg, ArgAction};
+use clap_complete::Shell;
+
+use crate::Command;
+
+/// Name of the hidden subcommand called back by the generated shell scripts.
+pub(crate) const COMPLETE_SUBCMD: &str = "__complete";
+
+/// Split the text before the cursor into the finished words and the word
+/// being typed, following the same quoting rules as [`shell_words::split`].
+///
+/// Returns the unquoted finished words, the byte offset where the current
+/// word starts and the unquoted current word. Unlike `shell_words::split`
+/// this never fails, so an unterminated quote simply extends to the cursor.
+pub(crate) fn split_partial(line: &str) -> (Vec<String>, usize, String) {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut start = line.len();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if !in_word {
+            if quote.is_none() && c.is_whitespace() {
+                continue;
+            }
+            in_word = true;
+            start = i;
+        }
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), c) => word.push(c),
+            (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') => match chars.peek() {
+                Some(&(_, n @ ('"' | '\\' | '$' | '`'))) => {
+                    word.push(n);
+                    chars.next();
+                }
+                Some(&(_, '\n')) => {
+                    chars.next();
+                }
+                _ => word.push('\\'),
+            },
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => match chars.next() {
+                Some((_, '\n')) | None => {}
+                Some((_, n)) => word.push(n),
+            },
+            (None, c) if c.is_whitespace() => {
+                words.push(std::mem::take(&mut word));
+                in_word = false;
+            }
+            (None, c) => word.push(c),
+        }
+    }
+
+    if !in_word {
+        start = line.len();
+    }
+    (words, start, word)
+}
+
+/// Compute completion candidates for `partial`, given the finished `words`
+/// typed after the name of `cmd`.
+///
+/// `cmd` should be built (see [`clap::Command::build`]) so that generated
+/// arguments like `--help` are taken into account. `root` is the [`Command`]
+/// that `cmd` was taken from, it's walked along to find the completers
+/// registered with [`Command::arg_completer`].
+pub(crate) fn candidates<'ctx, Ctx>(
+    cmd: &clap::Command,
+    root: &Command<'ctx, Ctx>,
+    ctx: &Ctx,
+    words: &[String],
+    partial: &str,
+) -> Vec<String> {
+    let mut walker = Walker::new(cmd, root);
+    for word in words {
+        walker.step(word);
+    }
+    let Walker {
+        cmd,
+        node,
+        pos_idx,
+        pending,
+        only_pos,
+        ..
+    } = walker;
+
+    let mut out = Vec::new();
+    let values = |arg: &Arg, partial: &str| -> Vec<String> {
+        let mut values: Vec<String> = possible_values(arg).collect();
+        if let Some(f) = node.and_then(|n| n.completers.get(arg.get_id().as_str())) {
+            values.extend(f(ctx, partial));
+        }
+        values
+    };
+    if let Some(arg) = pending {
+        out.extend(values(arg, partial));
+    } else if !only_pos && partial.starts_with("--") && partial.contains('=') {
+        let (flag, value) = partial.split_once('=').unwrap();
+        if let Some(arg) = find_long(cmd, &flag[2..]) {
+            out.extend(
+                values(arg, value)
+                    .into_iter()
+                    .map(|v| format!("{}={}", flag, v)),
+            );
+        }
+    } else if !only_pos && partial.starts_with('-') {
+        for arg in cmd.get_arguments().filter(|a| !a.is_hide_set()) {
+            if let Some(longs) = arg.get_long_and_visible_aliases() {
+                out.extend(longs.into_iter().map(|l| format!("--{}", l)));
+            }
+            if let Some(shorts) = arg.get_short_and_visible_aliases() {
+                out.extend(shorts.into_iter().map(|s| format!("-{}", s)));
+            }
+        }
+    } else {
+        if !only_pos && pos_idx == 0 {
+            for subcmd in cmd.get_subcommands().filter(|s| !s.is_hide_set()) {
+                out.push(subcmd.get_name().to_owned());
+                out.extend(subcmd.get_all_aliases().map(str::to_owned));
+            }
+        }
+        if let Some(arg) = positional(cmd, pos_idx) {
+            out.extend(values(arg, partial));
+        }
+    }
+
+    out.retain(|c| c.starts_with(partial));
+    out.sort();
+    out.dedup();
+    out
+}
+
+/// What a word typed after a command is, see [`Walker::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WordKind {
+    /// A flag, or `--`.
+    Flag,
+    /// The value of the previous flag.
+    Value,
+    Subcommand,
+    Positional,
+    /// Neither a subcommand nor a positional arg of the command.
+    Unknown,
+}
+
+/// Walks a command tree along the words typed after its name, following how
+/// clap parses them.
+pub(crate) struct Walker<'a, 'ctx, Ctx> {
+    /// The current command, that the next word is passed to.
+    pub(crate) cmd: &'a clap::Command,
+    node: Option<&'a Command<'ctx, Ctx>>,
+    // Index of the positional arg taking the next word.
+    pos_idx: usize,
+    // Number of words taken by positional args.
+    values: usize,
+    // Flag that takes the next word as its value.
+    pending: Option<&'a Arg>,
+    // Whether `--` was passed.
+    only_pos: bool,
+}
+
+impl<'a, 'ctx, Ctx> Walker<'a, 'ctx, Ctx> {
+    /// `cmd` should be built, see [`candidates`].
+    pub(crate) fn new(cmd: &'a clap::Command, root: &'a Command<'ctx, Ctx>) -> Self {
+        Self {
+            cmd,
+            node: Some(root),
+            pos_idx: 0,
+            values: 0,
+            pending: None,
+            only_pos: false,
+        }
+    }
+
+    /// Move past the unquoted `word`.
+    pub(crate) fn step(&mut self, word: &str) -> WordKind {
+        if self.pending.take().is_some() {
+            return WordKind::Value;
+        }
+        if !self.only_pos && word == "--" {
+            self.only_pos = true;
+            return WordKind::Flag;
+        }
+        if !self.only_pos && word.starts_with("--") {
+            if !word.contains('=') {
+                self.pending = find_long(self.cmd, &word[2..]).filter(|a| takes_value(a));
+            }
+            return WordKind::Flag;
+        }
+        if !self.only_pos && word.starts_with('-') && word.len() > 1 {
+            let shorts = &word[1..];
+            for (i, c) in shorts.char_indices() {
+                if let Some(arg) = find_short(self.cmd, c).filter(|a| takes_value(a)) {
+                    if i + c.len_utf8() == shorts.len() {
+                        self.pending = Some(arg);
+                    }
+                    break;
+                }
+            }
+            return WordKind::Flag;
+        }
+        if !self.only_pos && self.pos_idx == 0 {
+            if let Some(subcmd) = self.cmd.find_subcommand(word) {
+                self.cmd = subcmd;
+                self.node = self.node.and_then(|n| n.subcmds.get(subcmd.get_name()));
+                return WordKind::Subcommand;
+            }
+        }
+        let Some(arg) = positional(self.cmd, self.pos_idx) else {
+            self.pos_idx += 1;
+            return WordKind::Unknown;
+        };
+        if !is_multiple(arg) {
+            self.pos_idx += 1;
+        }
+        self.values += 1;
+        WordKind::Positional
+    }
+
+    /// Placeholder of what's expected next, if it's required: the value of a
+    /// flag or a positional arg.
+    pub(crate) fn required_next(&self) -> Option<String> {
+        let arg = match self.pending {
+            Some(arg) => arg,
+            // Skip a multiple positional arg that already has a value.
+            None if self.values == self.pos_idx => {
+                positional(self.cmd, self.pos_idx).filter(|a| a.is_required_set())?
+            }
+            None => return None,
+        };
+        let name = arg
+            .get_value_names()
+            .and_then(|names| names.first())
+            .map_or_else(|| arg.get_id().to_string(), ToString::to_string);
+        Some(format!("<{}>", name))
+    }
+}
+
+fn find_long<'a>(cmd: &'a clap::Command, name: &str) -> Option<&'a Arg> {
+    cmd.get_arguments().find(|a| {
+        a.get_long() == Some(name)
+            || a.get_all_aliases()
+                .is_some_and(|aliases| aliases.contains(&name))
+    })
+}
+
+fn find_short(cmd: &clap::Command, c: char) -> Option<&Arg> {
+    cmd.get_arguments().find(|a| {
+        a.get_short() == Some(c)
+            || a.get_all_short_aliases()
+                .is_some_and(|aliases| aliases.contains(&c))
+    })
+}
+
+fn positional(cmd: &clap::Command, idx: usize) -> Option<&Arg> {
+    let mut positionals: Vec<_> = cmd.get_positionals().collect();
+    positionals.sort_by_key(|a| a.get_index());
+    positionals
+        .get(idx)
+        .copied()
+        .or_else(|| positionals.last().copied().filter(|a| is_multiple(a)))
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values() && arg.get_num_args().is_none_or(|r| r.min_values() > 0)
+}
+
+fn is_multiple(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append)
+        || arg.get_num_args().is_some_and(|r| r.max_values() > 1)
+}
+
+fn possible_values(arg: &Arg) -> impl Iterator<Item = String> {
+    arg.get_possible_values()
+        .into_iter()
+        .filter(|v| !v.is_hide_set())
+        .map(|v| v.get_name().to_owned())
+}
+
+/// Generate a completion script that calls back into the [`COMPLETE_SUBCMD`]
+/// of `bin_name`, so completers registered on the arguments are used.
+///
+/// Returns `None` for shells without a dynamic script.
+pub(crate) fn dynamic_script(shell: Shell, bin_name: &str) -> Option<String> {
+    let func = format!(
+        "_{}",
+        bin_name.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    );
+    let script = match shell {
+        Shell::Bash => format!(
+            r#"{func}() {{
+    local IFS=$'\n'
+    COMPREPLY=($("{bin}" {sub} -- "${{COMP_WORDS[@]:1:COMP_CWORD}}" 2>/dev/null))
+}}
+
+complete -o bashdefault -o default -F {func} {bin}
+"#,
+            func = func,
+            bin = bin_name,
+            sub = COMPLETE_SUBCMD,
+        ),
+        Shell::Zsh => format!(
+            r#"#compdef {bin}
+
+{func}() {{
+    local -a candidates
+    candidates=(${{(f)"$("{bin}" {sub} -- "${{(@)words[2,CURRENT]}}" 2>/dev/null)"}})
+    compadd -a candidates || _files
+}}
+
+if [ "$funcstack[1]" = "{func}" ]; then
+    {func} "$@"
+else
+    compdef {func} {bin}
+fi
+"#,
+            func = func,
+            bin = bin_name,
+            sub = COMPLETE_SUBCMD,
+        ),
+        Shell::Fish => format!(
+            "complete -c {bin} -f -a '(\"{bin}\" {sub} -- (commandline -opc)[2..] (commandline -ct) 2>/dev/null)'\n",
+            bin = bin_name,
+            sub = COMPLETE_SUBCMD,
+        ),
+        _ => return None,
+    };
+    Some(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_partial_words() {
+        let cases: &[(&str, &[&str], usize, &str)] = &[
+            ("", &[], 0, ""),
+            ("a", &[], 0, "a"),
+            ("a ", &["a"], 2, ""),
+            ("a b", &["a"], 2, "b"),
+            ("a  'b c' d", &["a", "b c"], 9, "d"),
+            // An unterminated quote extends to the cursor.
+            ("a 'b c", &["a"], 2, "b c"),
+            (r#"a "b \" \$ \x"#, &["a"], 2, r#"b " $ \x"#),
+            (r"a b\ c", &["a"], 2, "b c"),
+            (r"a \", &["a"], 2, ""),
+        ];
+        for (line, words, start, partial) in cases {
+            let expected = (
+                words.iter().map(|&w| w.to_owned()).collect(),
+                *start,
+                partial.to_string(),
+            );
+            assert_eq!(split_partial(line), expected, "{:?}", line);
+        }
+    }
+}
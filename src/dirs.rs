@@ -0,0 +1,30 @@
+use std::path::{Path, PathBuf};
+
+fn non_empty_var(var: &str) -> Option<PathBuf> {
+    std::env::var_os(var)
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+/// The home directory of the current user.
+pub(crate) fn home_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        non_empty_var("USERPROFILE")
+    } else {
+        non_empty_var("HOME")
+    }
+}
+
+/// The base directory for user data, i.e. `$XDG_DATA_HOME`.
+pub(crate) fn data_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        non_empty_var("APPDATA")
+    } else {
+        non_empty_var("XDG_DATA_HOME").or_else(|| home_dir().map(|home| home.join(".local/share")))
+    }
+}
+
+/// The default rc file of the REPL for the root command `name`.
+pub(crate) fn rc_file(name: &str) -> Option<PathBuf> {
+    home_dir().map(|home| Path::new(&home).join(format!(".{}rc", name)))
+}
@@ -0,0 +1,198 @@
+use clap::builder::StyledStr;
+use clap::ColorChoice;
+use std::error::Error;
+use std::fmt::{self, Write as _};
+use std::io::{IsTerminal, Write};
+
+pub(crate) type ErrorRenderFn<'ctx> = dyn Fn(&anyhow::Error) + 'ctx;
+
+/// An error meant for the users of a command, e.g. about their input.
+///
+/// It's rendered with its message only, while other errors are rendered with
+/// their whole chain of causes.
+#[derive(Debug)]
+pub struct UserError {
+    message: String,
+    code: i32,
+}
+
+impl UserError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self {
+            message: message.into(),
+            code: 1,
+        }
+    }
+
+    /// Set the exit code of the process, see [`exit_code`]. Defaults to 1.
+    pub fn exit_code(mut self, code: i32) -> Self {
+        self.code = code;
+        self
+    }
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for UserError {}
+
+/// Get the exit code for `e`: the one of the first [`clap::Error`] or
+/// [`UserError`] in its chain, 2 for usage errors, otherwise 1.
+///
+/// It's the exit code of [`Command::exec_and_exit`], and `$?` in the REPL.
+///
+/// [`Command::exec_and_exit`]: crate::Command::exec_and_exit
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    for e in e.chain() {
+        if let Some(e) = e.downcast_ref::<clap::Error>() {
+            return e.exit_code();
+        }
+        if let Some(e) = e.downcast_ref::<UserError>() {
+            return e.code;
+        }
+    }
+    1
+}
+
+/// Render `e` with the styles of `cmd`.
+///
+/// The chain of causes stops at the first [`UserError`] or [`clap::Error`],
+/// the contexts added on top of them are kept. Clap errors are rendered by
+/// clap, with their usage.
+pub(crate) fn render(e: &anyhow::Error, cmd: &clap::Command) -> StyledStr {
+    let style = cmd.get_styles().get_error();
+    let mut out = StyledStr::new();
+    let chain: Vec<&(dyn Error + 'static)> = e.chain().collect();
+    let user_facing = chain
+        .iter()
+        .position(|e| e.is::<UserError>() || e.is::<clap::Error>());
+
+    // Writing to a `StyledStr` can't fail.
+    match user_facing {
+        Some(i) => {
+            if let Some(clap_error) = chain[i].downcast_ref::<clap::Error>() {
+                for context in &chain[..i] {
+                    let _ = writeln!(out, "{}:", context);
+                }
+                let _ = write!(out, "{}", clap_error.render().ansi());
+                return out;
+            }
+            let message: Vec<String> = chain[..=i].iter().map(ToString::to_string).collect();
+            let _ = writeln!(
+                out,
+                "{}error:{} {}",
+                style.render(),
+                style.render_reset(),
+                message.join(": ")
+            );
+        }
+        None => {
+            let _ = writeln!(
+                out,
+                "{}error:{} {}",
+                style.render(),
+                style.render_reset(),
+                chain[0]
+            );
+            if chain.len() > 1 {
+                let _ = writeln!(out, "\nCaused by:");
+                for cause in &chain[1..] {
+                    let _ = writeln!(out, "    {}", cause);
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Print `e` to stderr as [`render`] does, with colors if the color choice of
+/// `cmd` allows it.
+pub(crate) fn report(e: &anyhow::Error, cmd: &clap::Command) {
+    let rendered = render(e, cmd);
+    let styled = match cmd.get_color() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => std::io::stderr().is_terminal(),
+    };
+    let mut stderr = std::io::stderr().lock();
+    let _ = if styled {
+        write!(stderr, "{}", rendered.ansi())
+    } else {
+        write!(stderr, "{}", rendered)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// An error caused by a user error.
+    #[derive(Debug)]
+    struct Wrapper(UserError);
+
+    impl fmt::Display for Wrapper {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("wrapper")
+        }
+    }
+
+    impl Error for Wrapper {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn rendered(e: &anyhow::Error) -> String {
+        render(e, &clap::Command::new("app")).to_string()
+    }
+
+    #[test]
+    fn render_chains() {
+        let e = anyhow!("inner").context("middle").context("outer");
+        assert_eq!(
+            rendered(&e),
+            "error: outer\n\nCaused by:\n    middle\n    inner\n"
+        );
+
+        // The contexts of a user error are on the same line.
+        let e = anyhow::Error::from(UserError::new("bad input"))
+            .context("in `cmd`")
+            .context("line 2");
+        assert_eq!(rendered(&e), "error: line 2: in `cmd`: bad input\n");
+
+        // Clap errors are rendered by clap.
+        let clap_error = clap::Command::new("app")
+            .try_get_matches_from(["app", "--bogus"])
+            .unwrap_err();
+        let e = anyhow::Error::from(clap_error).context("line 3");
+        let rendered = rendered(&e);
+        assert!(
+            rendered.starts_with("line 3:\nerror: unexpected argument '--bogus' found\n"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("Usage: app"), "{}", rendered);
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(&anyhow!("failed")), 1);
+        assert_eq!(exit_code(&UserError::new("bad").into()), 1);
+
+        // The code of the first user or clap error in the chain.
+        let user = || UserError::new("bad").exit_code(7);
+        assert_eq!(exit_code(&anyhow::Error::from(user()).context("ctx")), 7);
+        assert_eq!(exit_code(&Wrapper(user()).into()), 7);
+
+        let cmd = clap::Command::new("app");
+        let usage = cmd.clone().try_get_matches_from(["app", "-x"]);
+        assert_eq!(exit_code(&usage.unwrap_err().into()), 2);
+        let help = cmd.try_get_matches_from(["app", "--help"]);
+        assert_eq!(exit_code(&help.unwrap_err().into()), 0);
+    }
+}
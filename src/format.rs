@@ -0,0 +1,251 @@
+use clap::{value_parser, Arg, ArgMatches, ValueEnum};
+use std::cell::Cell;
+use std::fmt;
+
+pub(crate) const OUTPUT_ARG: &str = "output";
+
+thread_local! {
+    // Format used when `--output` is not given, set by the REPL.
+    static DEFAULT_FORMAT: Cell<OutputFormat> = const { Cell::new(OutputFormat::Text) };
+}
+
+/// How the values returned by [`value_handler`]s are rendered.
+///
+/// [`value_handler`]: crate::Command::value_handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum OutputFormat {
+    /// Plain text, one line per item or field.
+    #[default]
+    Text,
+    /// Pretty printed JSON.
+    Json,
+    /// YAML, with the `yaml` feature.
+    #[cfg(feature = "yaml")]
+    Yaml,
+    /// Aligned columns, one row per item.
+    Table,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.to_possible_value().expect("no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// The `--output <FORMAT>` arg.
+pub(crate) fn arg() -> Arg {
+    Arg::new(OUTPUT_ARG)
+        .long(OUTPUT_ARG)
+        .value_name("FORMAT")
+        .value_parser(value_parser!(OutputFormat))
+        .global(true)
+        .help("Format of the output")
+}
+
+/// The format given by `--output` in `m`, or the default one.
+pub(crate) fn format_of(m: &ArgMatches) -> OutputFormat {
+    // `try_` since the arg may not be defined, or be another one.
+    m.try_get_one::<OutputFormat>(OUTPUT_ARG)
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or_else(default_format)
+}
+
+pub(crate) fn default_format() -> OutputFormat {
+    DEFAULT_FORMAT.with(Cell::get)
+}
+
+pub(crate) fn set_default_format(format: OutputFormat) {
+    DEFAULT_FORMAT.with(|f| f.set(format));
+}
+
+/// Run `f` with `format` as the default format, which is restored after.
+pub(crate) fn with_default_format<T>(format: OutputFormat, f: impl FnOnce() -> T) -> T {
+    struct Guard(OutputFormat);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            set_default_format(self.0);
+        }
+    }
+
+    let _guard = Guard(default_format());
+    set_default_format(format);
+    f()
+}
+
+#[cfg(feature = "serde")]
+pub(crate) use self::render::render;
+
+#[cfg(feature = "serde")]
+mod render {
+    use anyhow::Result;
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use super::OutputFormat;
+
+    /// Render `value` in `format`, ending with a newline unless it's empty.
+    pub(crate) fn render<T: Serialize + ?Sized>(value: &T, format: OutputFormat) -> Result<String> {
+        Ok(match format {
+            OutputFormat::Json => serde_json::to_string_pretty(value)? + "\n",
+            #[cfg(feature = "yaml")]
+            OutputFormat::Yaml => serde_yaml::to_string(value)?,
+            OutputFormat::Text => lines(text(&serde_json::to_value(value)?)),
+            OutputFormat::Table => lines(table(&serde_json::to_value(value)?)),
+        })
+    }
+
+    fn lines(lines: Vec<String>) -> String {
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+
+    /// A scalar as is, nested values as JSON.
+    fn cell(value: &Value) -> String {
+        match value {
+            Value::Null => String::new(),
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        }
+    }
+
+    /// A line per item of a list or field of a record, where the items
+    /// that are records are on a line each too.
+    fn text(value: &Value) -> Vec<String> {
+        match value {
+            Value::Null => Vec::new(),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| match item {
+                    Value::Object(fields) => fields
+                        .iter()
+                        .map(|(name, value)| field(name, value))
+                        .collect::<Vec<_>>()
+                        .join("  "),
+                    other => cell(other),
+                })
+                .collect(),
+            Value::Object(fields) => fields
+                .iter()
+                .map(|(name, value)| field(name, value))
+                .collect(),
+            other => vec![cell(other)],
+        }
+    }
+
+    fn field(name: &str, value: &Value) -> String {
+        format!("{}: {}", name, cell(value)).trim_end().to_owned()
+    }
+
+    fn table(value: &Value) -> Vec<String> {
+        let (header, rows): (Vec<String>, Vec<Vec<String>>) = match value {
+            // A list of records, with a column per field of any of them.
+            Value::Array(items) if items.iter().all(Value::is_object) => {
+                let mut header: Vec<String> = Vec::new();
+                for fields in items.iter().filter_map(Value::as_object) {
+                    for name in fields.keys() {
+                        if !header.contains(name) {
+                            header.push(name.clone());
+                        }
+                    }
+                }
+                let rows = items
+                    .iter()
+                    .map(|item| {
+                        header
+                            .iter()
+                            .map(|name| item.get(name).map(cell).unwrap_or_default())
+                            .collect()
+                    })
+                    .collect();
+                (header, rows)
+            }
+            Value::Array(items) => (
+                vec!["value".into()],
+                items.iter().map(|item| vec![cell(item)]).collect(),
+            ),
+            Value::Object(fields) => (
+                vec!["field".into(), "value".into()],
+                fields
+                    .iter()
+                    .map(|(name, value)| vec![name.clone(), cell(value)])
+                    .collect(),
+            ),
+            other => return text(other),
+        };
+        if header.is_empty() {
+            return Vec::new();
+        }
+
+        let header: Vec<String> = header.iter().map(|name| name.to_uppercase()).collect();
+        let mut widths: Vec<usize> = header.iter().map(|name| name.chars().count()).collect();
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        std::iter::once(&header)
+            .chain(&rows)
+            .map(|row| {
+                let line: Vec<String> = row
+                    .iter()
+                    .zip(&widths)
+                    .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                    .collect();
+                line.join("  ").trim_end().to_owned()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "serde")]
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn restore_default_format() {
+        let format = with_default_format(OutputFormat::Json, || {
+            set_default_format(OutputFormat::Table);
+            default_format()
+        });
+        assert_eq!(format, OutputFormat::Table);
+        assert_eq!(default_format(), OutputFormat::Text);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn render_text() {
+        let text = |value| render(&value, OutputFormat::Text).unwrap();
+        assert_eq!(text(json!(null)), "");
+        assert_eq!(text(json!("a")), "a\n");
+        assert_eq!(text(json!(["a", 1, [2]])), "a\n1\n[2]\n");
+        assert_eq!(
+            text(json!({"name": "a", "tags": null, "size": {"kb": 1}})),
+            "name: a\ntags:\nsize: {\"kb\":1}\n"
+        );
+        assert_eq!(
+            text(json!([{"name": "a", "id": 1}, {"name": "b", "tags": null}, 2])),
+            "name: a  id: 1\nname: b  tags:\n2\n"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn render_table() {
+        let table = |value| render(&value, OutputFormat::Table).unwrap();
+        assert_eq!(table(json!([])), "");
+        assert_eq!(
+            table(json!([{"name": "a", "id": 1}, {"name": "long", "tags": ["x"]}])),
+            "NAME  ID  TAGS\na     1\nlong      [\"x\"]\n"
+        );
+        assert_eq!(table(json!(["a", "b"])), "VALUE\na\nb\n");
+        assert_eq!(
+            table(json!({"name": "a", "id": 1})),
+            "FIELD  VALUE\nname   a\nid     1\n"
+        );
+    }
+}
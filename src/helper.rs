@@ -0,0 +1,201 @@
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::{Hinter, HistoryHinter};
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use clap::ColorChoice;
+use std::borrow::Cow;
+
+use crate::builtin;
+use crate::complete::{self, Walker, WordKind};
+use crate::highlight::{self, HINT};
+use crate::line;
+use crate::session::Session;
+
+/// Rustyline helper backed by the command tree of a REPL session.
+pub(crate) struct ReplHelper<'a, 'ctx, Ctx> {
+    session: &'a Session<'a, 'ctx, Ctx>,
+    // Built clap tree of the root command, with the generated help args and
+    // subcommand.
+    cmd: clap::Command,
+    // The current prompt, and its styled version.
+    prompt: Option<(String, String)>,
+    // For the targets of redirections and shell escapes.
+    files: FilenameCompleter,
+    history: HistoryHinter,
+    // Whether the line continues an incomplete one, so it's not a command.
+    continuation: bool,
+}
+
+impl<'a, 'ctx, Ctx> ReplHelper<'a, 'ctx, Ctx> {
+    pub(crate) fn new(session: &'a Session<'a, 'ctx, Ctx>) -> Self {
+        let mut cmd = session.root().cmd.clone();
+        cmd.build();
+        Self {
+            session,
+            cmd,
+            prompt: None,
+            files: FilenameCompleter::new(),
+            history: HistoryHinter::new(),
+            continuation: false,
+        }
+    }
+
+    /// The built clap command of the current scope.
+    fn scope_cmd(&self) -> &clap::Command {
+        self.session
+            .scope()
+            .iter()
+            .fold(&self.cmd, |cmd, name| cmd.find_subcommand(name).unwrap())
+    }
+
+    /// Whether `name` is a command, other than the subcommands of the scope.
+    fn is_known(&self, name: &str) -> bool {
+        name == ".."
+            || builtin::find(self.session.builtins(), name).is_some()
+            || self.session.aliases().contains_key(name)
+    }
+
+    /// Display `prompt` as `styled`.
+    pub(crate) fn set_prompt(&mut self, prompt: &str, styled: String) {
+        self.prompt = Some((prompt.to_owned(), styled));
+    }
+
+    /// Set whether the next lines continue an incomplete one, which are then
+    /// neither completed, hinted nor highlighted.
+    pub(crate) fn set_continuation(&mut self, yes: bool) {
+        self.continuation = yes;
+    }
+}
+
+impl<Ctx> Completer for ReplHelper<'_, '_, Ctx> {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        context: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        if self.continuation {
+            return Ok((pos, Vec::new()));
+        }
+        // Shell escapes only get file names.
+        if self.session.shell_escape(line).is_some() {
+            return self.files.complete(line, pos, context);
+        }
+        // Only the last of the chained commands is completed.
+        let segment = line::split_commands(&line[..pos]).pop().unwrap();
+        if let Some(redirect) = segment.redirect {
+            let (start, pairs) = self
+                .files
+                .complete_path(redirect.target, redirect.target.len())?;
+            return Ok((redirect.start + start, pairs));
+        }
+        let (words, start, partial) = complete::split_partial(segment.text);
+        let start = segment.start + start;
+        let scope = self.session.scope();
+        let cmd = self.scope_cmd();
+        let ctx = self.session.ctx();
+        let mut candidates =
+            complete::candidates(cmd, self.session.scope_cmd(), &*ctx, &words, &partial);
+        if words.is_empty() {
+            candidates.extend(
+                self.session
+                    .aliases()
+                    .keys()
+                    .filter(|name| name.starts_with(&partial))
+                    .cloned(),
+            );
+        }
+        if !scope.is_empty() && words.is_empty() {
+            let builtins = self.session.builtins().iter().map(|b| b.get_name());
+            candidates.extend(
+                std::iter::once("..")
+                    .chain(builtins)
+                    .filter(|name| name.starts_with(&partial))
+                    .map(str::to_owned),
+            );
+        }
+        let pairs = candidates
+            .into_iter()
+            .map(|c| Pair {
+                replacement: shell_words::quote(&c).into_owned(),
+                display: c,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl<Ctx> Hinter for ReplHelper<'_, '_, Ctx> {
+    type Hint = String;
+
+    /// Hint the rest of the line from the history, or else the next required
+    /// arg.
+    fn hint(&self, line: &str, pos: usize, context: &Context<'_>) -> Option<String> {
+        if self.continuation || pos < line.len() || self.session.shell_escape(line).is_some() {
+            return None;
+        }
+        if let Some(hint) = self.history.hint(line, pos, context) {
+            return Some(hint);
+        }
+        let segment = line::split_commands(line).pop()?;
+        let (words, _, partial) = complete::split_partial(segment.text);
+        if words.is_empty() || !partial.is_empty() || segment.redirect.is_some() {
+            return None;
+        }
+        let mut walker = Walker::new(self.scope_cmd(), self.session.scope_cmd());
+        for word in &words {
+            if walker.step(word) == WordKind::Unknown {
+                return None;
+            }
+        }
+        walker.required_next()
+    }
+}
+
+impl<Ctx> Highlighter for ReplHelper<'_, '_, Ctx> {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.continuation
+            || self.cmd.get_color() == ColorChoice::Never
+            || self.session.shell_escape(line).is_some()
+        {
+            return Cow::Borrowed(line);
+        }
+        Cow::Owned(highlight::highlight(
+            line,
+            self.scope_cmd(),
+            self.session.scope_cmd(),
+            &|name| self.is_known(name),
+            self.session.external_pipes(),
+        ))
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if self.cmd.get_color() == ColorChoice::Never {
+            return Cow::Borrowed(hint);
+        }
+        Cow::Owned(format!("{}{}{}", HINT.render(), hint, HINT.render_reset()))
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        true
+    }
+
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        match &self.prompt {
+            Some((plain, styled)) if plain == prompt => Cow::Borrowed(styled),
+            _ => Cow::Borrowed(prompt),
+        }
+    }
+}
+
+impl<Ctx> Validator for ReplHelper<'_, '_, Ctx> {}
+
+impl<Ctx> Helper for ReplHelper<'_, '_, Ctx> {}
@@ -0,0 +1,82 @@
+use clap::builder::styling::{AnsiColor, Style};
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::complete::{Walker, WordKind};
+use crate::line::{self, Join};
+use crate::Command;
+
+const COMMAND: Style = AnsiColor::Green.on_default().bold();
+const UNKNOWN: Style = AnsiColor::Red.on_default();
+const FLAG: Style = AnsiColor::Cyan.on_default();
+const QUOTED: Style = AnsiColor::Yellow.on_default();
+pub(crate) const HINT: Style = Style::new().dimmed();
+
+/// Highlight the subcommands, flags and quoted words of `line`, and the
+/// words that are neither subcommands nor args of their command.
+///
+/// `cmd` is the built clap tree of `root`. `is_known` tells if a first word
+/// that's not a subcommand is still a command, e.g. an alias, and
+/// `external_pipes` if piped commands may be external programs.
+pub(crate) fn highlight<Ctx>(
+    line: &str,
+    cmd: &clap::Command,
+    root: &Command<'_, Ctx>,
+    is_known: &dyn Fn(&str) -> bool,
+    external_pipes: bool,
+) -> String {
+    let mut spans: Vec<(Range<usize>, Style)> = Vec::new();
+    for segment in line::split_commands(line) {
+        // Unknown words are not reported past a command that's not in the
+        // tree.
+        let mut walker = Some(Walker::new(cmd, root));
+        for (i, (start, raw)) in line::words(segment.text).into_iter().enumerate() {
+            let word = shell_words::split(raw)
+                .ok()
+                .and_then(|words| words.into_iter().next())
+                .unwrap_or_else(|| raw.to_owned());
+            let mut kind = walker.as_mut().map(|w| w.step(&word));
+            if i == 0 && kind == Some(WordKind::Unknown) {
+                if is_known(&word) {
+                    kind = Some(WordKind::Subcommand);
+                    walker = None;
+                } else if external_pipes && segment.join == Join::Pipe {
+                    // Piped commands may be external programs.
+                    kind = None;
+                    walker = None;
+                }
+            }
+            let style = if raw.starts_with(['\'', '"']) {
+                Some(QUOTED)
+            } else {
+                match kind {
+                    Some(WordKind::Flag) => Some(FLAG),
+                    Some(WordKind::Subcommand) => Some(COMMAND),
+                    Some(WordKind::Unknown) => Some(UNKNOWN),
+                    None if word.starts_with('-') => Some(FLAG),
+                    _ => None,
+                }
+            };
+            if let Some(style) = style {
+                let start = segment.start + start;
+                spans.push((start..start + raw.len(), style));
+            }
+        }
+    }
+
+    let mut highlighted = String::with_capacity(line.len());
+    let mut end = 0;
+    for (range, style) in spans {
+        highlighted.push_str(&line[end..range.start]);
+        let _ = write!(
+            highlighted,
+            "{}{}{}",
+            style.render(),
+            &line[range.clone()],
+            style.render_reset()
+        );
+        end = range.end;
+    }
+    highlighted.push_str(&line[end..]);
+    highlighted
+}
@@ -0,0 +1,147 @@
+use rustyline::config::Configurer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Editor, Helper};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::dirs::data_dir;
+
+pub(crate) type FilterFn = dyn Fn(&str) -> bool;
+
+/// Persistent history of the REPL.
+pub struct History {
+    path: Option<PathBuf>,
+    max_size: usize,
+    dedupe: bool,
+    ignore_space: bool,
+    // Shared with the session, for the persisted aliases.
+    filter: Option<Rc<FilterFn>>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl History {
+    /// Create a history stored at the default path, which is
+    /// `$XDG_DATA_HOME/<name>/history` where `<name>` is the name of the
+    /// REPL's root command.
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            max_size: 1000,
+            dedupe: true,
+            ignore_space: true,
+            filter: None,
+        }
+    }
+
+    /// Store the history at `path` instead of the default one.
+    pub fn path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Max number of entries to keep. Defaults to 1000.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Don't add a line that's the same as the previous entry. Defaults to true.
+    pub fn dedupe(mut self, yes: bool) -> Self {
+        self.dedupe = yes;
+        self
+    }
+
+    /// Don't add lines starting with a space. Defaults to true.
+    pub fn ignore_space(mut self, yes: bool) -> Self {
+        self.ignore_space = yes;
+        self
+    }
+
+    /// Only add the lines for which `filter` returns true.
+    ///
+    /// Rejected lines are kept out of both the session and the history file,
+    /// which is useful for commands that take secrets. Aliases with a
+    /// rejected value are not persisted either.
+    pub fn filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&str) -> bool + 'static,
+    {
+        self.filter = Some(Rc::new(filter));
+        self
+    }
+
+    pub(crate) fn line_filter(&self) -> Option<Rc<FilterFn>> {
+        self.filter.clone()
+    }
+
+    pub(crate) fn configure<H: Helper>(
+        &self,
+        editor: &mut Editor<H, DefaultHistory>,
+    ) -> rustyline::Result<()> {
+        editor.set_max_history_size(self.max_size)?;
+        editor.set_history_ignore_dups(self.dedupe)?;
+        editor.set_history_ignore_space(self.ignore_space);
+        Ok(())
+    }
+
+    /// Resolve the history file for the root command `name`.
+    pub(crate) fn resolve_path(&self, name: &str) -> Option<PathBuf> {
+        self.path
+            .clone()
+            .or_else(|| data_dir().map(|dir| dir.join(name).join("history")))
+    }
+
+    /// Where the aliases are persisted, next to the history file at `path`.
+    pub(crate) fn aliases_path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_owned();
+        name.push(".aliases");
+        path.with_file_name(name)
+    }
+
+    pub(crate) fn load<H: Helper>(
+        &self,
+        editor: &mut Editor<H, DefaultHistory>,
+        path: &Path,
+    ) -> rustyline::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        match editor.load_history(path) {
+            Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Remove the entries persisted at `path`.
+    pub(crate) fn clear_file(path: &Path) -> std::io::Result<()> {
+        // The editor creates it again when appending the next line.
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Add `line` to the history, and append it to the history file.
+    pub(crate) fn add<H: Helper>(
+        &self,
+        editor: &mut Editor<H, DefaultHistory>,
+        path: Option<&Path>,
+        line: &str,
+    ) -> rustyline::Result<()> {
+        if self.filter.as_ref().is_some_and(|f| !f(line)) {
+            return Ok(());
+        }
+        if editor.add_history_entry(line)? {
+            if let Some(path) = path {
+                editor.append_history(path)?;
+            }
+        }
+        Ok(())
+    }
+}
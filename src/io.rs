@@ -0,0 +1,143 @@
+use clap::builder::StyledStr;
+use clap::ColorChoice;
+use std::cell::RefCell;
+use std::io::{self, IsTerminal, Read, Write};
+use std::rc::Rc;
+
+thread_local! {
+    // Stack of redirections, the last one is the current output.
+    static OUTPUTS: RefCell<Vec<Box<dyn Write>>> = const { RefCell::new(Vec::new()) };
+    // Same for the input, which is redirected by pipes.
+    static INPUTS: RefCell<Vec<Box<dyn Read>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Handle to the output of the running command, see [`output`].
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    _priv: (),
+}
+
+/// Get the output of the running command.
+///
+/// It's stdout, unless the REPL redirected it, e.g. with `cmd > file`.
+/// Handlers should write their output here instead of to stdout.
+pub fn output() -> Output {
+    Output { _priv: () }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        OUTPUTS.with(|outputs| match outputs.borrow_mut().last_mut() {
+            Some(w) => w.write(buf),
+            None => io::stdout().write(buf),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        OUTPUTS.with(|outputs| match outputs.borrow_mut().last_mut() {
+            Some(w) => w.flush(),
+            None => io::stdout().flush(),
+        })
+    }
+}
+
+/// Handle to the input of the running command, see [`input`].
+#[derive(Debug, Clone, Copy)]
+pub struct Input {
+    _priv: (),
+}
+
+/// Get the input of the running command.
+///
+/// It's stdin, unless the command is piped into, e.g. with `list | count`.
+/// Wrap it in a [`BufReader`](std::io::BufReader) to read it by lines.
+pub fn input() -> Input {
+    Input { _priv: () }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        INPUTS.with(|inputs| match inputs.borrow_mut().last_mut() {
+            Some(r) => r.read(buf),
+            None => io::stdin().read(buf),
+        })
+    }
+}
+
+/// Whether [`output`] is redirected, i.e. it's not stdout.
+pub(crate) fn is_redirected() -> bool {
+    OUTPUTS.with(|outputs| !outputs.borrow().is_empty())
+}
+
+/// Whether [`output`] is the terminal, i.e. stdout is a terminal and it's not
+/// redirected.
+pub(crate) fn is_terminal() -> bool {
+    !is_redirected() && io::stdout().is_terminal()
+}
+
+/// Print `s` to [`output`], with colors if `color` allows it.
+pub(crate) fn print_styled(s: &StyledStr, color: ColorChoice) -> io::Result<()> {
+    // Only the terminal gets colors by default, not the redirected output.
+    let styled = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => is_terminal(),
+    };
+    if styled {
+        write!(output(), "{}", s.ansi())
+    } else {
+        write!(output(), "{}", s)
+    }
+}
+
+/// Run `f` with [`output`] redirected to `writer`, which is flushed after.
+pub(crate) fn redirect_output<R>(
+    writer: Box<dyn Write>,
+    f: impl FnOnce() -> R,
+) -> (R, io::Result<()>) {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            OUTPUTS.with(|outputs| outputs.borrow_mut().pop());
+        }
+    }
+
+    OUTPUTS.with(|outputs| outputs.borrow_mut().push(writer));
+    let guard = Guard;
+    let res = f();
+    let flushed = output().flush();
+    drop(guard);
+    (res, flushed)
+}
+
+/// Run `f` and return what it wrote to [`output`].
+pub(crate) fn capture_output<R>(f: impl FnOnce() -> R) -> (R, Vec<u8>) {
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Rc::new(RefCell::new(Vec::new()));
+    let (res, _) = redirect_output(Box::new(Capture(buf.clone())), f);
+    (res, buf.take())
+}
+
+/// Run `f` with [`input`] redirected to `data`.
+pub(crate) fn redirect_input<R>(data: Vec<u8>, f: impl FnOnce() -> R) -> R {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            INPUTS.with(|inputs| inputs.borrow_mut().pop());
+        }
+    }
+
+    INPUTS.with(|inputs| inputs.borrow_mut().push(Box::new(io::Cursor::new(data))));
+    let _guard = Guard;
+    f()
+}
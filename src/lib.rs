@@ -1,23 +1,63 @@
 use anyhow::{bail, Result};
 use clap::builder::{IntoResettable, Str, StyledStr};
 use clap::{Arg, ArgMatches, ColorChoice};
-use rustyline::error::ReadlineError;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::OsString;
-use rustyline::DefaultEditor;
+use std::io::Write;
+
+use crate::error::ErrorRenderFn;
+
+mod builtin;
+mod complete;
+mod dirs;
+mod error;
+mod format;
+mod helper;
+mod highlight;
+mod history;
+mod io;
+mod line;
+mod process;
+mod repl;
+mod script;
+mod session;
+mod suggest;
+
+pub use error::{exit_code, UserError};
+pub use format::OutputFormat;
+pub use history::History;
+pub use io::{input, output, Input, Output};
+pub use repl::{repl, Interrupt, Repl, ReplError};
 
 pub use clap;
 pub use rustyline;
 pub use shell_words;
 
 
+thread_local! {
+    // Names of the commands being executed, from the root.
+    static EXECUTING: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
 type HandleFn<'ctx, Ctx> =
     dyn Fn(&Command<'ctx, Ctx>, &ArgMatches, &mut Ctx) -> Result<()> + 'ctx;
 
+type CompleteFn<'ctx, Ctx> = dyn Fn(&Ctx, &str) -> Vec<String> + 'ctx;
+
+type MiddlewareFn<'ctx, Ctx> =
+    dyn Fn(&[&str], &ArgMatches, &mut Ctx, &mut dyn FnMut(&mut Ctx) -> Result<()>) -> Result<()>
+        + 'ctx;
+
 pub struct Command<'ctx, Ctx: 'ctx> {
     cmd: clap::Command,
-    handler: Box<HandleFn<'ctx, Ctx>>,
+    // Dispatches to the subcommands if `None`.
+    handler: Option<Box<HandleFn<'ctx, Ctx>>>,
     subcmds: HashMap<String, Self>,
+    completers: HashMap<String, Box<CompleteFn<'ctx, Ctx>>>,
+    middleware: Vec<Box<MiddlewareFn<'ctx, Ctx>>>,
+    repl_scope: bool,
+    error_renderer: Option<Box<ErrorRenderFn<'ctx>>>,
 }
 
 impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
@@ -25,11 +65,34 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
     pub fn new<S: Into<Str>>(name: S) -> Self {
         Self {
             cmd: clap::Command::new(name),
-            handler: Box::new(Self::dispatch_subcmd),
+            handler: None,
             subcmds: HashMap::new(),
+            completers: HashMap::new(),
+            middleware: Vec::new(),
+            repl_scope: false,
+            error_renderer: None,
         }
     }
 
+    /// Create a new command with args defined by `T`, e.g. a struct deriving
+    /// [`clap::Args`].
+    ///
+    /// The handler receives the args parsed into `T`. Subcommands are then
+    /// dispatched as usual.
+    pub fn from_args<T, S, H>(name: S, handler: H) -> Self
+    where
+        T: clap::Args,
+        S: Into<Str>,
+        H: Fn(T, &mut Ctx) -> Result<()> + 'ctx,
+    {
+        let mut this = Self::new(name);
+        this.cmd = T::augment_args(this.cmd);
+        this.handler(move |cmd, m, ctx| {
+            handler(T::from_arg_matches(m)?, ctx)?;
+            cmd.dispatch_subcmd(m, ctx)
+        })
+    }
+
     /// (Re)Sets this command's app name.
     pub fn name<S: Into<Str>>(mut self, name: S) -> Self {
         self.cmd = self.cmd.name(name);
@@ -85,11 +148,105 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
         self
     }
 
+    /// Set a completer for the values of the arg with `id`.
+    ///
+    /// The completer receives the context and the partial input, and returns
+    /// the candidates. It's used by the REPL and the scripts generated by
+    /// [`with_completions_subcmd`], in addition to the arg's possible values.
+    ///
+    /// [`with_completions_subcmd`]: Command::with_completions_subcmd
+    pub fn arg_completer<S, F>(mut self, id: S, completer: F) -> Self
+    where
+        S: Into<String>,
+        F: Fn(&Ctx, &str) -> Vec<String> + 'ctx,
+    {
+        self.completers.insert(id.into(), Box::new(completer));
+        self
+    }
+
+    /// Enter this command as a scope when it's invoked without args in the REPL.
+    ///
+    /// Lines are then dispatched relative to it, until `..` or `exit`.
+    pub fn repl_scope(mut self, yes: bool) -> Self {
+        self.repl_scope = yes;
+        self
+    }
+
+    /// Set how [`render_error`] renders errors, which is also used by the
+    /// REPL. Only the one of the root command is used.
+    ///
+    /// [`render_error`]: Command::render_error
+    pub fn error_renderer<F>(mut self, renderer: F) -> Self
+    where
+        F: Fn(&anyhow::Error) + 'ctx,
+    {
+        self.error_renderer = Some(Box::new(renderer));
+        self
+    }
+
+    /// Render an error returned by this command, e.g. by [`exec`].
+    ///
+    /// Unless set with [`error_renderer`], it's printed to stderr, with colors
+    /// as set by [`color`]. A [`UserError`] is printed with its message only,
+    /// other errors with their chain of causes.
+    ///
+    /// [`exec`]: Command::exec
+    /// [`error_renderer`]: Command::error_renderer
+    /// [`color`]: Command::color
+    pub fn render_error(&self, e: &anyhow::Error) {
+        match &self.error_renderer {
+            Some(renderer) => renderer(e),
+            None => error::report(e, &self.cmd),
+        }
+    }
+
     pub fn handler<H>(mut self, handler: H) -> Self
     where
         H: Fn(&Self, &ArgMatches, &mut Ctx) -> Result<()> + 'ctx,
     {
-        self.handler = Box::new(handler);
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Set a handler returning a value, which is rendered to [`output`] in
+    /// the format given by `--output`, see [`with_output_arg`].
+    ///
+    /// Without `--output`, it's rendered as text, or in the default format of
+    /// the REPL. Subcommands are not dispatched.
+    ///
+    /// [`with_output_arg`]: Command::with_output_arg
+    #[cfg(feature = "serde")]
+    pub fn value_handler<T, H>(self, handler: H) -> Self
+    where
+        T: serde::Serialize,
+        H: Fn(&Self, &ArgMatches, &mut Ctx) -> Result<T> + 'ctx,
+    {
+        self.handler(move |cmd, m, ctx| {
+            let value = handler(cmd, m, ctx)?;
+            let rendered = format::render(&value, format::format_of(m))?;
+            output().write_all(rendered.as_bytes())?;
+            Ok(())
+        })
+    }
+
+    /// Wrap the execution of this command and its subcommands with
+    /// `middleware`, e.g. to log, time or check permissions.
+    ///
+    /// It receives the path of the executed command from the root, e.g.
+    /// `["app", "db", "list"]`, its matches, the context, and `next` which
+    /// executes the command and returns the result. Not calling `next` skips
+    /// the command, e.g. to deny it with an error.
+    ///
+    /// It runs once per execution, also in REPL scopes, but not when nothing
+    /// is executed, e.g. when entering the REPL, nor for completions. The
+    /// middleware of a command runs inside the one of its parent, and the
+    /// first added is the outermost.
+    pub fn middleware<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&[&str], &ArgMatches, &mut Ctx, &mut dyn FnMut(&mut Ctx) -> Result<()>) -> Result<()>
+            + 'ctx,
+    {
+        self.middleware.push(Box::new(middleware));
         self
     }
 
@@ -138,18 +295,55 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
         let completions = completions_without_handler.handler(move |_cmd, m, _ctx| {
             let shell: clap_complete::Shell =
                 m.get_one::<String>("shell").unwrap().parse().unwrap();
-            let mut stdout = std::io::stdout();
+            let mut output = output();
             let bin_name = cmd_for_completions.get_name();
-            clap_complete::generate(
-                shell,
-                &mut cmd_for_completions.clone(),
-                bin_name,
-                &mut stdout,
-            );
+            if let Some(script) = complete::dynamic_script(shell, bin_name) {
+                output.write_all(script.as_bytes())?;
+            } else {
+                clap_complete::generate(
+                    shell,
+                    &mut cmd_for_completions.clone(),
+                    bin_name,
+                    &mut output,
+                );
+            }
             Ok(())
         });
 
-        self.subcommand(completions)
+        // Handled by `dispatch_subcmd`, since it needs the whole command tree.
+        let complete = clap::Command::new(complete::COMPLETE_SUBCMD)
+            .hide(true)
+            .arg(
+                Arg::new("words")
+                    .num_args(0..)
+                    .trailing_var_arg(true)
+                    .allow_hyphen_values(true),
+            );
+        let mut this = self.subcommand(completions);
+        this.cmd = this.cmd.subcommand(complete);
+        this
+    }
+
+    /// Add the global `--output <FORMAT>` arg, to choose the format of the
+    /// values returned by [`value_handler`]s: `text`, `json`, `table`, or
+    /// `yaml` with the `yaml` feature.
+    ///
+    /// Call it after adding the subcommands, so that it's also available in
+    /// their REPL scopes.
+    ///
+    /// [`value_handler`]: Command::value_handler
+    pub fn with_output_arg(self) -> Self {
+        self.with_global_arg(format::arg())
+    }
+
+    fn with_global_arg(mut self, arg: Arg) -> Self {
+        self.cmd = self.cmd.arg(arg.clone());
+        self.subcmds = self
+            .subcmds
+            .into_iter()
+            .map(|(name, subcmd)| (name, subcmd.with_global_arg(arg.clone())))
+            .collect();
+        self
     }
 
     #[allow(unused)]
@@ -158,9 +352,133 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
         self.exec_with(&m, ctx)
     }
 
+    /// Execute this command with the process args, then exit the process.
+    ///
+    /// Errors are rendered with [`render_error`], and the exit code is given
+    /// by [`exit_code`]: 2 for usage errors, 1 for other errors unless they're
+    /// a [`UserError`] with another code, and 0 on success. The help and
+    /// version are printed by clap as usual.
+    ///
+    /// [`render_error`]: Command::render_error
+    pub fn exec_and_exit(&self, ctx: &mut Ctx) -> ! {
+        let m = self
+            .cmd
+            .clone()
+            .try_get_matches()
+            .unwrap_or_else(|e| e.exit());
+        let code = match self.exec_with(&m, ctx) {
+            Ok(()) => 0,
+            Err(e) => {
+                self.render_error(&e);
+                exit_code(&e)
+            }
+        };
+        let _ = output().flush();
+        std::process::exit(code)
+    }
+
     /// Execute this command with context and args.
     pub fn exec_with(&self, m: &ArgMatches, ctx: &mut Ctx) -> Result<()> {
-        (self.handler)(self, m, ctx)
+        self.exec_under(&[], m, ctx, self.handler.as_deref())
+    }
+
+    /// Execute the subcommand in `m` of this command, which is a REPL scope
+    /// below `parents`, the commands above it from the root.
+    ///
+    /// The handler of the scope doesn't run again, but the middleware of all
+    /// of them applies.
+    pub(crate) fn dispatch_under(
+        &self,
+        parents: &[&Self],
+        m: &ArgMatches,
+        ctx: &mut Ctx,
+    ) -> Result<()> {
+        self.exec_under(parents, m, ctx, None)
+    }
+
+    /// Run `handler` with this command as a subcommand of `parents`, inside
+    /// their middleware and its own. A `None` handler dispatches to the
+    /// subcommands.
+    fn exec_under(
+        &self,
+        parents: &[&Self],
+        m: &ArgMatches,
+        ctx: &mut Ctx,
+        handler: Option<&HandleFn<'ctx, Ctx>>,
+    ) -> Result<()> {
+        struct Guard(usize);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                EXECUTING.with(|names| {
+                    let mut names = names.borrow_mut();
+                    let len = names.len() - self.0;
+                    names.truncate(len);
+                });
+            }
+        }
+
+        let names: Vec<String> = parents
+            .iter()
+            .chain([&self])
+            .map(|cmd| cmd.get_name().to_owned())
+            .collect();
+        // Non-empty when dispatched by the handler of a parent.
+        let mut path = EXECUTING.with(|executing| executing.borrow().clone());
+        path.extend(names.iter().cloned());
+        EXECUTING.with(|executing| executing.borrow_mut().extend(names));
+        let _guard = Guard(parents.len() + 1);
+
+        let middleware: Vec<&MiddlewareFn<'ctx, Ctx>> = parents
+            .iter()
+            .chain([&self])
+            .flat_map(|cmd| cmd.middleware.iter().map(Box::as_ref))
+            .collect();
+        // Nothing is executed when only dispatching without a subcommand,
+        // e.g. when entering the REPL, and completions are not commands.
+        let subcmd = m.subcommand_name();
+        let skip =
+            subcmd == Some(complete::COMPLETE_SUBCMD) || (handler.is_none() && subcmd.is_none());
+        if middleware.is_empty() || skip {
+            return self.run_handler(handler, m, ctx);
+        }
+
+        // The middleware sees the executed command, i.e. the innermost one.
+        let mut leaf = m;
+        while let Some((name, subcmd_matches)) = leaf.subcommand() {
+            path.push(name.to_owned());
+            leaf = subcmd_matches;
+        }
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        self.exec_wrapped(&middleware, &path, leaf, m, ctx, handler)
+    }
+
+    fn exec_wrapped(
+        &self,
+        middleware: &[&MiddlewareFn<'ctx, Ctx>],
+        path: &[&str],
+        leaf: &ArgMatches,
+        m: &ArgMatches,
+        ctx: &mut Ctx,
+        handler: Option<&HandleFn<'ctx, Ctx>>,
+    ) -> Result<()> {
+        match middleware.split_first() {
+            Some((outer, inner)) => outer(path, leaf, ctx, &mut |ctx| {
+                self.exec_wrapped(inner, path, leaf, m, ctx, handler)
+            }),
+            None => self.run_handler(handler, m, ctx),
+        }
+    }
+
+    fn run_handler(
+        &self,
+        handler: Option<&HandleFn<'ctx, Ctx>>,
+        m: &ArgMatches,
+        ctx: &mut Ctx,
+    ) -> Result<()> {
+        match handler {
+            Some(handler) => handler(self, m, ctx),
+            None => self.dispatch_subcmd(m, ctx),
+        }
     }
 
     pub fn exec_from<I, T>(&self, iter: I, ctx: &mut Ctx) -> Result<()>
@@ -176,6 +494,8 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
         if let Some((subcmd_name, subcmd_matches)) = m.subcommand() {
             if let Some(subcmd) = self.subcmds.get(subcmd_name) {
                 subcmd.exec_with(subcmd_matches, ctx)?;
+            } else if subcmd_name == complete::COMPLETE_SUBCMD {
+                self.print_completions(subcmd_matches, ctx)?;
             } else {
                 // TODO: this may be an unreachable branch.
                 bail!("no subcommand handler for `{}`", subcmd_name);
@@ -184,6 +504,29 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
         Ok(())
     }
 
+    fn print_completions(&self, m: &ArgMatches, ctx: &Ctx) -> Result<()> {
+        let mut words: Vec<String> = m
+            .get_many::<String>("words")
+            .map(|words| words.cloned().collect())
+            .unwrap_or_default();
+        let partial = words.pop().unwrap_or_default();
+
+        let mut cmd = self.cmd.clone();
+        cmd.build();
+        let mut stdout = std::io::stdout().lock();
+        for candidate in complete::candidates(&cmd, self, ctx, &words, &partial) {
+            writeln!(stdout, "{}", candidate)?;
+        }
+        Ok(())
+    }
+
+    /// Find the subcommand by its name or aliases.
+    fn find_subcmd(&self, name: &str) -> Option<&Self> {
+        self.cmd
+            .find_subcommand(name)
+            .and_then(|c| self.subcmds.get(c.get_name()))
+    }
+
     /// Get name of the underlaying clap App.
     pub fn get_name(&self) -> &str {
         self.cmd.get_name()
@@ -205,37 +548,96 @@ impl<'ctx, Ctx: 'ctx> Command<'ctx, Ctx> {
     }
 }
 
-pub fn repl<'ctx, Ctx>(cmd: Command<'ctx, Ctx>, mut ctx: Ctx, prompt: &str) {
-    let m = cmd.get_matches();
-    cmd.exec_with(&m, &mut ctx).unwrap();
-
-    if m.subcommand().is_none() {
-        let mut editor = DefaultEditor::new().unwrap();
-        loop {
-            let line = editor.readline(prompt);
-            match line {
-                Ok(line) => {
-                    editor.add_history_entry(&line).unwrap();
-
-                    let args = match shell_words::split(&line) {
-                        Ok(args) => args,
-                        Err(e) => {
-                            println!("parse error: `{}`", e);
-                            continue;
-                        }
-                    };
-                    let input = std::iter::once(cmd.get_name().into()).chain(args);
-                    if let Err(e) = cmd.exec_from(input, &mut ctx) {
-                        println!("{:?}", e);
-                    }
-                }
-                Err(ReadlineError::Eof) => break,
-                Err(ReadlineError::Interrupted) => println!("press CTRL-D to exit"),
-                Err(e) => {
-                    println!("readline error {}", e);
-                    break;
-                }
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::Session;
+
+    type Log = Vec<String>;
+
+    /// Log `name` around the execution, with the path of the command.
+    fn logged(name: &'static str) -> Box<MiddlewareFn<'static, Log>> {
+        Box::new(move |path, _, log, next| {
+            log.push(format!("{} {}", name, path.join(" ")));
+            let res = next(log);
+            log.push(format!("/{}", name));
+            res
+        })
+    }
+
+    fn leaf(name: &'static str) -> Command<'static, Log> {
+        Command::new(name).handler(move |_, _, log: &mut Log| {
+            log.push(name.to_owned());
+            Ok(())
+        })
+    }
+
+    fn app() -> Command<'static, Log> {
+        let db = Command::new("db")
+            .repl_scope(true)
+            .handler(|cmd, m, log: &mut Log| {
+                log.push("db".to_owned());
+                cmd.dispatch_subcmd(m, log)
+            })
+            .middleware(logged("c"))
+            .subcommand(leaf("list"));
+        Command::new("app")
+            .middleware(logged("a"))
+            .middleware(logged("b"))
+            .subcommand(db)
+    }
+
+    #[test]
+    fn middleware_nesting() {
+        let mut log = Log::new();
+        app().exec_from(["app", "db", "list"], &mut log).unwrap();
+        assert_eq!(
+            log,
+            [
+                "a app db list",
+                "b app db list",
+                "c app db list",
+                "db",
+                "list",
+                "/c",
+                "/b",
+                "/a"
+            ]
+        );
+
+        // Nothing is executed without a subcommand.
+        let mut log = Log::new();
+        app().exec_from(["app"], &mut log).unwrap();
+        assert_eq!(log, Vec::<String>::new());
+    }
+
+    #[test]
+    fn middleware_short_circuit() {
+        let app = app().middleware(|_, _, _, _| bail!("denied"));
+        let mut log = Log::new();
+        let e = app.exec_from(["app", "db", "list"], &mut log).unwrap_err();
+        assert_eq!(e.to_string(), "denied");
+        assert_eq!(log, ["a app db list", "b app db list", "/b", "/a"]);
+    }
+
+    #[test]
+    fn middleware_in_repl_scope() {
+        let app = app();
+        let session = Session::new(&app, Log::new(), Vec::new(), false, false);
+        session.exec_line("db", None).unwrap();
+        session.exec_line("list", None).unwrap();
+        // The handler of the scope doesn't run again.
+        assert_eq!(
+            session.into_ctx(),
+            [
+                "a app db list",
+                "b app db list",
+                "c app db list",
+                "list",
+                "/c",
+                "/b",
+                "/a"
+            ]
+        );
     }
 }
@@ -0,0 +1,505 @@
+use anyhow::{anyhow, Result};
+
+/// How a command is joined to the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Join {
+    /// `;`, or the first command of a line.
+    Always,
+    /// `&&`
+    IfOk,
+    /// `||`
+    IfErr,
+    /// `|`, the output of the previous command is the input of this one.
+    Pipe,
+}
+
+impl Join {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Always => ";",
+            Self::IfOk => "&&",
+            Self::IfErr => "||",
+            Self::Pipe => "|",
+        }
+    }
+}
+
+/// A command in a line.
+#[derive(Debug)]
+pub(crate) struct Segment<'a> {
+    pub(crate) join: Join,
+    /// Byte offset of `text` in the line.
+    pub(crate) start: usize,
+    pub(crate) text: &'a str,
+    pub(crate) redirect: Option<Redirect<'a>>,
+}
+
+/// Redirection of a command's output with `> file` or `>> file`.
+#[derive(Debug)]
+pub(crate) struct Redirect<'a> {
+    pub(crate) append: bool,
+    /// Byte offset of `target` in the line.
+    pub(crate) start: usize,
+    /// The unsplit text after the operator.
+    pub(crate) target: &'a str,
+}
+
+/// Split `line` into commands joined by `;`, `&&`, `||` or `|`, each of them
+/// optionally redirected with `>` or `>>`.
+///
+/// Operators are only recognized outside of quotes and comments, so each
+/// command can then be split with [`shell_words::split`]. This never fails,
+/// empty commands are kept and it's up to the caller to reject them.
+pub(crate) fn split_commands(line: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut join = Join::Always;
+    let mut start = 0;
+    // Offset and length of the redirection operator in the current command.
+    let mut redirect: Option<(usize, usize)> = None;
+    let mut quote: Option<char> = None;
+    let mut word_start = true;
+
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let at_word_start = std::mem::replace(&mut word_start, false);
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => {
+                chars.next();
+            }
+            // A comment lasts until the end of the line.
+            (None, '#') if at_word_start => break,
+            (None, c) if c.is_whitespace() => word_start = true,
+            (None, ';' | '&' | '|') => {
+                let op = match (c, chars.peek()) {
+                    (';', _) => Some((Join::Always, 1)),
+                    ('&', Some(&(_, '&'))) => Some((Join::IfOk, 2)),
+                    ('|', Some(&(_, '|'))) => Some((Join::IfErr, 2)),
+                    ('|', _) => Some((Join::Pipe, 1)),
+                    _ => None,
+                };
+                if let Some((next_join, len)) = op {
+                    segments.push(segment(line, join, start, i, redirect.take()));
+                    if len == 2 {
+                        chars.next();
+                    }
+                    join = next_join;
+                    start = i + len;
+                    word_start = true;
+                }
+            }
+            (None, '>') if redirect.is_none() => {
+                let len = if chars.next_if(|&(_, c)| c == '>').is_some() {
+                    2
+                } else {
+                    1
+                };
+                redirect = Some((i, len));
+                word_start = true;
+            }
+            _ => {}
+        }
+    }
+    segments.push(segment(line, join, start, line.len(), redirect));
+    segments
+}
+
+/// Split `text` into its words, with their byte offsets, keeping their
+/// quotes and escapes.
+pub(crate) fn words(text: &str) -> Vec<(usize, &str)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut quote: Option<char> = None;
+
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if quote.is_none() && c.is_whitespace() {
+            if let Some(start) = start.take() {
+                words.push((start, &text[start..i]));
+            }
+            continue;
+        }
+        start.get_or_insert(i);
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                chars.next();
+            }
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            _ => {}
+        }
+    }
+    if let Some(start) = start {
+        words.push((start, &text[start..]));
+    }
+    words
+}
+
+/// Expand the variables in `text` with `lookup`, outside of single quotes and
+/// unless the `$` is escaped.
+///
+/// Variables are written `$name`, `${name}`, `$?` or `$@`. A value may be
+/// several words, like `$@`, which are quoted so they are kept as is when
+/// `text` is split, or joined with spaces inside double quotes.
+pub(crate) fn expand(text: &str, lookup: &dyn Fn(&str) -> Result<Vec<String>>) -> Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut quote: Option<char> = None;
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (_, '\\') => {
+                expanded.push(c);
+                if let Some((_, c)) = chars.next() {
+                    expanded.push(c);
+                }
+                continue;
+            }
+            (_, '$') => {
+                let rest = &text[i + 1..];
+                let name = if rest.starts_with(['?', '@']) {
+                    Some((&rest[..1], 1))
+                } else if let Some(braced) = rest.strip_prefix('{') {
+                    let end = braced
+                        .find('}')
+                        .ok_or_else(|| anyhow!("missing `}}` after `${{`"))?;
+                    Some((&braced[..end], end + 2))
+                } else {
+                    let len = rest
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(rest.len());
+                    (len > 0).then(|| (&rest[..len], len))
+                };
+                if let Some((name, len)) = name {
+                    let words = lookup(name)?;
+                    if quote.is_some() {
+                        for c in words.join(" ").chars() {
+                            if matches!(c, '"' | '\\' | '$' | '`') {
+                                expanded.push('\\');
+                            }
+                            expanded.push(c);
+                        }
+                    } else {
+                        expanded.push_str(&shell_words::join(words));
+                    }
+                    while chars.next_if(|&(j, _)| j <= i + len).is_some() {}
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+    }
+    Ok(expanded)
+}
+
+/// Whether `text` refers to positional parameters, e.g. `$1` or `$@`.
+pub(crate) fn has_params(text: &str) -> bool {
+    text.match_indices('$').any(|(i, _)| {
+        let rest = text[i + 1..].strip_prefix('{').unwrap_or(&text[i + 1..]);
+        rest.starts_with(|c: char| c.is_ascii_digit() || c == '@')
+    })
+}
+
+/// Whether `name` can be used as a variable, i.e. it's an identifier.
+pub(crate) fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// How an incomplete line continues on the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Continuation {
+    /// The line ends with a `\`, which is removed with the line break.
+    Escape,
+    /// A quote is not closed, the line break is part of the quoted string.
+    Quote,
+}
+
+impl Continuation {
+    /// Append the `next` line to `input`.
+    pub(crate) fn join(self, input: &mut String, next: &str) {
+        match self {
+            Self::Escape => {
+                input.pop();
+            }
+            Self::Quote => input.push('\n'),
+        }
+        input.push_str(next);
+    }
+}
+
+/// Whether `line` is incomplete, i.e. it has an unterminated quote or ends
+/// with an escaped line break.
+pub(crate) fn continuation(line: &str) -> Option<Continuation> {
+    let mut quote: Option<char> = None;
+    let mut word_start = true;
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let at_word_start = std::mem::replace(&mut word_start, false);
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => {}
+            // Skips the escaped char, if there's one.
+            (_, '\\') if chars.next().is_none() => return Some(Continuation::Escape),
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '#') if at_word_start => return None,
+            (None, ';' | '&' | '|' | '>') => word_start = true,
+            (None, c) if c.is_whitespace() => word_start = true,
+            _ => {}
+        }
+    }
+    quote.map(|_| Continuation::Quote)
+}
+
+fn segment(
+    line: &str,
+    join: Join,
+    start: usize,
+    end: usize,
+    redirect: Option<(usize, usize)>,
+) -> Segment<'_> {
+    match redirect {
+        Some((at, len)) => Segment {
+            join,
+            start,
+            text: &line[start..at],
+            redirect: Some(Redirect {
+                append: len == 2,
+                start: at + len,
+                target: &line[at + len..end],
+            }),
+        },
+        None => Segment {
+            join,
+            start,
+            text: &line[start..end],
+            redirect: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A command, trimmed, with whether it's appended to its redirect target.
+    type Command<'a> = (Join, &'a str, Option<(bool, &'a str)>);
+
+    /// The commands of `line`.
+    fn commands(line: &str) -> Vec<Command<'_>> {
+        split_commands(line)
+            .into_iter()
+            .map(|s| {
+                let redirect = s.redirect.map(|r| (r.append, r.target.trim()));
+                (s.join, s.text.trim(), redirect)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_chains() {
+        use Join::*;
+        let cases: &[(&str, &[Command])] = &[
+            ("a", &[(Always, "a", None)]),
+            (
+                "a; b && c || d",
+                &[
+                    (Always, "a", None),
+                    (Always, "b", None),
+                    (IfOk, "c", None),
+                    (IfErr, "d", None),
+                ],
+            ),
+            (
+                "a;b&&c",
+                &[(Always, "a", None), (Always, "b", None), (IfOk, "c", None)],
+            ),
+            // A single `&` is not an operator.
+            ("a & b", &[(Always, "a & b", None)]),
+            // Empty commands are kept.
+            ("a && ", &[(Always, "a", None), (IfOk, "", None)]),
+            ("; a", &[(Always, "", None), (Always, "a", None)]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(commands(line), *expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn split_quotes_and_comments() {
+        let cases = [
+            "echo 'a; b' \"c && d\"",
+            r#"echo "a \" && b""#,
+            r"echo a \; b \&& c",
+            // The operators of a comment are ignored.
+            "echo a # b; c",
+            "# a; b",
+        ];
+        for line in cases {
+            assert_eq!(commands(line), [(Join::Always, line, None)], "{:?}", line);
+        }
+        // Not a comment in the middle of a word.
+        assert_eq!(
+            commands("echo a#b; c"),
+            [(Join::Always, "echo a#b", None), (Join::Always, "c", None)]
+        );
+    }
+
+    #[test]
+    fn split_redirects() {
+        use Join::*;
+        let cases: &[(&str, &[Command])] = &[
+            ("a > out", &[(Always, "a", Some((false, "out")))]),
+            ("a >> out", &[(Always, "a", Some((true, "out")))]),
+            ("a>out", &[(Always, "a", Some((false, "out")))]),
+            ("a > out b", &[(Always, "a", Some((false, "out b")))]),
+            (
+                "a > out; b >> $f",
+                &[
+                    (Always, "a", Some((false, "out"))),
+                    (Always, "b", Some((true, "$f"))),
+                ],
+            ),
+            ("echo 'a > b'", &[(Always, "echo 'a > b'", None)]),
+            (r"echo a \> b", &[(Always, r"echo a \> b", None)]),
+            ("echo a # > b", &[(Always, "echo a # > b", None)]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(commands(line), *expected, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn split_pipes() {
+        use Join::*;
+        let cases: &[(&str, &[Command])] = &[
+            ("a | b", &[(Always, "a", None), (Pipe, "b", None)]),
+            (
+                "a || b | c",
+                &[(Always, "a", None), (IfErr, "b", None), (Pipe, "c", None)],
+            ),
+            (
+                "a > out | b > $f",
+                &[
+                    (Always, "a", Some((false, "out"))),
+                    (Pipe, "b", Some((false, "$f"))),
+                ],
+            ),
+            ("echo 'a | b'", &[(Always, "echo 'a | b'", None)]),
+            (r"echo a \| b", &[(Always, r"echo a \| b", None)]),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(commands(line), *expected, "{:?}", line);
+        }
+    }
+
+    /// Expand `text` with `args` as `$@`, and split it into words.
+    fn expand_words(text: &str, args: &[&str]) -> Result<Vec<String>> {
+        let lookup = |name: &str| match name {
+            "@" => Ok(args.iter().map(|&a| a.to_owned()).collect()),
+            "x" => Ok(vec!["one".to_owned()]),
+            "s" => Ok(vec!["a b".to_owned()]),
+            "?" => Ok(vec!["0".to_owned()]),
+            _ => Err(anyhow!("undefined variable: `{}`", name)),
+        };
+        Ok(shell_words::split(&expand(text, &lookup)?)?)
+    }
+
+    #[test]
+    fn expand_variables() {
+        let cases: &[(&str, &[&str])] = &[
+            ("cmd $x ${x}y $?", &["cmd", "one", "oney", "0"]),
+            // A value is a single word, unless it's in double quotes.
+            ("cmd $s", &["cmd", "a b"]),
+            (r#"cmd "$s $x""#, &["cmd", "a b one"]),
+            (r#"cmd "$x \"y\"""#, &["cmd", r#"one "y""#]),
+            // Not expanded in single quotes or when escaped.
+            ("cmd '$x' \\$x", &["cmd", "$x", "$x"]),
+            // A lone `$` is kept.
+            ("cmd $ a$", &["cmd", "$", "a$"]),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(expand_words(text, &[]).unwrap(), *expected, "{:?}", text);
+        }
+
+        assert!(expand_words("cmd $y", &[]).is_err());
+        assert!(expand_words("cmd ${x", &[]).is_err());
+    }
+
+    #[test]
+    fn expand_args() {
+        let cases: &[(&str, &[&str], &[&str])] = &[
+            // `$@` expands to as many words as there are args.
+            ("cmd $@", &[], &["cmd"]),
+            ("cmd $@", &["a"], &["cmd", "a"]),
+            ("cmd $@", &["a", "b c"], &["cmd", "a", "b c"]),
+            ("$@", &[], &[]),
+            // Joined in double quotes.
+            (r#"cmd "$@""#, &["a", "b"], &["cmd", "a b"]),
+            (r#"cmd "$@""#, &[], &["cmd", ""]),
+            // Args are not expanded again.
+            ("$@", &["$x", "'a"], &["$x", "'a"]),
+        ];
+        for (text, args, expected) in cases {
+            assert_eq!(expand_words(text, args).unwrap(), *expected, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn params() {
+        assert!(has_params("a $1"));
+        assert!(has_params("a ${2}"));
+        assert!(has_params("a $@"));
+        assert!(!has_params("a $x $? \\$"));
+    }
+
+    #[test]
+    fn continuations() {
+        let cases = [
+            ("a", None),
+            ("a 'b", Some(Continuation::Quote)),
+            ("a \"b' c", Some(Continuation::Quote)),
+            ("a 'b \"' c", None),
+            (r"a \", Some(Continuation::Escape)),
+            (r"a \\", None),
+            (r"a 'b\", Some(Continuation::Quote)),
+            ("a # 'b", None),
+            ("a#'b", Some(Continuation::Quote)),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(continuation(line), expected, "{:?}", line);
+        }
+
+        let mut input = r"a \".to_owned();
+        Continuation::Escape.join(&mut input, "b");
+        assert_eq!(input, "a b");
+        let mut input = "a 'b".to_owned();
+        Continuation::Quote.join(&mut input, "c'");
+        assert_eq!(input, "a 'b\nc'");
+    }
+
+    #[test]
+    fn split_words() {
+        assert_eq!(
+            words(r#" a 'b c' "d \" e"  f\ g "#),
+            [(1, "a"), (3, "'b c'"), (9, r#""d \" e""#), (19, r"f\ g")]
+        );
+        assert_eq!(words("  "), []);
+    }
+}
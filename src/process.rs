@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::process::Stdio;
+
+use crate::io::{self, output};
+use crate::UserError;
+
+/// Run the external program `args[0]` with `input` as its stdin, or the
+/// inherited one if it's `None`.
+///
+/// Its stdout goes to [`output`], directly to stdout if it's not redirected.
+pub(crate) fn run(args: &[String], input: Option<Vec<u8>>) -> Result<()> {
+    let (program, args) = args.split_first().context("no program to run")?;
+    let mut cmd = std::process::Command::new(program);
+    cmd.args(args);
+    spawn(&mut cmd, program, input)
+}
+
+/// Run `line` with the system shell, `sh -c` or `cmd /C` on Windows.
+pub(crate) fn shell(line: &str) -> Result<()> {
+    #[cfg(windows)]
+    let mut cmd = {
+        use std::os::windows::process::CommandExt;
+
+        // `cmd` does its own parsing of the line, which must not be quoted.
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.arg("/C").raw_arg(line);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c").arg(line);
+        cmd
+    };
+    spawn(&mut cmd, line.trim(), None)
+}
+
+fn spawn(cmd: &mut std::process::Command, name: &str, input: Option<Vec<u8>>) -> Result<()> {
+    output().flush()?;
+    let _interrupts = IgnoreInterrupts::new();
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stdout(if io::is_redirected() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .spawn()
+        .with_context(|| format!("failed to run `{}`", name))?;
+
+    // Feed the input from another thread, so the child can't block on a full
+    // stdout while we're still writing its stdin.
+    let writer = child.stdin.take().zip(input).map(|(mut stdin, input)| {
+        std::thread::spawn(move || {
+            // The child may exit without reading all of it, e.g. `head`.
+            let _ = stdin.write_all(&input);
+        })
+    });
+    if let Some(mut stdout) = child.stdout.take() {
+        std::io::copy(&mut stdout, &mut output())?;
+    }
+    let status = child.wait()?;
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    if !status.success() {
+        let error = UserError::new(format!("`{}` failed, {}", name, status));
+        return Err(error.exit_code(status.code().unwrap_or(1)).into());
+    }
+    Ok(())
+}
+
+/// Keep CTRL-C from killing the REPL while a child runs, it only interrupts
+/// the child.
+///
+/// A no-op handler is used instead of ignoring the signal, since handlers
+/// are reset in the child but ignored signals are not.
+struct IgnoreInterrupts {
+    #[cfg(unix)]
+    prev: libc::sighandler_t,
+}
+
+impl IgnoreInterrupts {
+    #[cfg(unix)]
+    fn new() -> Self {
+        extern "C" fn noop(_: libc::c_int) {}
+        let handler = noop as extern "C" fn(libc::c_int);
+        // SAFETY: the handler is async-signal-safe, it does nothing.
+        let prev = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        Self { prev }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for IgnoreInterrupts {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: restoring the handler that was installed before.
+        unsafe {
+            libc::signal(libc::SIGINT, self.prev);
+        }
+    }
+}
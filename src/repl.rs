@@ -0,0 +1,656 @@
+use anyhow::{Context, Result};
+use clap::builder::StyledStr;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{value_parser, Arg, ArgAction};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::builtin::{self, Flow};
+use crate::dirs;
+use crate::format;
+use crate::helper::ReplHelper;
+use crate::line;
+use crate::script;
+use crate::session::Session;
+use crate::{exit_code, output, Command, History, OutputFormat, UserError};
+
+const SCRIPT_ARG: &str = "script";
+const KEEP_GOING_ARG: &str = "keep-going";
+
+type PromptFn<'ctx, Ctx> = dyn Fn(&Ctx) -> StyledStr + 'ctx;
+
+/// What to do when the user presses CTRL-C at the prompt.
+#[derive(Debug, Clone)]
+pub enum Interrupt {
+    /// Print a message and continue.
+    Message(String),
+    /// Continue with a new prompt.
+    Ignore,
+    /// Exit the REPL, like CTRL-D.
+    Exit,
+}
+
+impl Default for Interrupt {
+    fn default() -> Self {
+        Self::Message("press CTRL-D to exit".into())
+    }
+}
+
+/// Errors that end the REPL.
+///
+/// [`Repl::run`] returns them wrapped in an [`anyhow::Error`], use
+/// [`anyhow::Error::downcast_ref`] to tell them apart. The process should
+/// exit with their [`exit_code`], e.g. the one of the last failed line of a
+/// script.
+#[derive(Debug)]
+pub enum ReplError {
+    /// Executing the process args failed.
+    Startup(anyhow::Error),
+    /// The line editor failed, e.g. it can't access the terminal.
+    Editor(ReadlineError),
+    /// Running a script failed, either to read it or on some of its lines.
+    Script(anyhow::Error),
+}
+
+impl fmt::Display for ReplError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Startup(_) => write!(f, "failed to execute the process args"),
+            Self::Editor(_) => write!(f, "line editor failed"),
+            Self::Script(_) => write!(f, "script failed"),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Startup(e) | Self::Script(e) => Some(e.as_ref()),
+            Self::Editor(e) => Some(e),
+        }
+    }
+}
+
+impl From<ReadlineError> for ReplError {
+    fn from(e: ReadlineError) -> Self {
+        Self::Editor(e)
+    }
+}
+
+/// A REPL running a [`Command`] on each line.
+///
+/// The process args are executed first. If no subcommand is given, it enters
+/// the REPL until EOF or an exit command.
+pub struct Repl<'ctx, Ctx> {
+    cmd: Command<'ctx, Ctx>,
+    ctx: Ctx,
+    prompt: Box<PromptFn<'ctx, Ctx>>,
+    continuation_prompt: String,
+    config: Config,
+    history: Option<History>,
+    banner: Option<String>,
+    exit_commands: Vec<String>,
+    interrupt: Interrupt,
+    builtins: bool,
+    shell_escape: bool,
+    external_pipes: bool,
+    script_args: bool,
+    keep_going: bool,
+    rc_file: Option<PathBuf>,
+    load_rc: bool,
+    output_format: OutputFormat,
+}
+
+impl<'ctx, Ctx> Repl<'ctx, Ctx> {
+    pub fn new(cmd: Command<'ctx, Ctx>, ctx: Ctx) -> Self {
+        let prompt = StyledStr::from(format!("{}> ", cmd.get_name()));
+        Self {
+            cmd,
+            ctx,
+            prompt: Box::new(move |_| prompt.clone()),
+            continuation_prompt: "...> ".to_owned(),
+            config: Config::default(),
+            history: None,
+            banner: None,
+            exit_commands: Vec::new(),
+            interrupt: Interrupt::default(),
+            builtins: false,
+            shell_escape: false,
+            external_pipes: false,
+            script_args: false,
+            keep_going: false,
+            rc_file: None,
+            load_rc: true,
+            output_format: OutputFormat::default(),
+        }
+    }
+
+    /// Set the prompt. Defaults to `<name>> `.
+    pub fn prompt<S: Into<StyledStr>>(self, prompt: S) -> Self {
+        let prompt = prompt.into();
+        self.prompt_with(move |_| prompt.clone())
+    }
+
+    /// Compute the prompt from the context before reading each line.
+    ///
+    /// The prompt may be styled with ANSI escape codes, they are not counted
+    /// in its width.
+    pub fn prompt_with<F, S>(mut self, prompt: F) -> Self
+    where
+        F: Fn(&Ctx) -> S + 'ctx,
+        S: Into<StyledStr>,
+    {
+        self.prompt = Box::new(move |ctx| prompt(ctx).into());
+        self
+    }
+
+    /// Set the prompt of the lines continuing an incomplete one, which has an
+    /// unterminated quote or ends with `\`. Defaults to `...> `.
+    pub fn continuation_prompt<S: Into<String>>(mut self, prompt: S) -> Self {
+        self.continuation_prompt = prompt.into();
+        self
+    }
+
+    /// Set the config of the underlying rustyline editor.
+    pub fn editor_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Persist the history as configured by `history`.
+    pub fn history(mut self, history: History) -> Self {
+        self.history = Some(history);
+        self
+    }
+
+    /// Set how errors returned by commands are printed, same as
+    /// [`Command::error_renderer`] on the root command.
+    pub fn error_renderer<F>(mut self, renderer: F) -> Self
+    where
+        F: Fn(&anyhow::Error) + 'ctx,
+    {
+        self.cmd = self.cmd.error_renderer(renderer);
+        self
+    }
+
+    /// Print `banner` when entering the REPL.
+    pub fn banner<S: Into<String>>(mut self, banner: S) -> Self {
+        self.banner = Some(banner.into());
+        self
+    }
+
+    /// Lines that exit the REPL, e.g. `exit` and `quit`.
+    pub fn exit_commands(mut self, cmds: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.exit_commands = cmds.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set what to do on CTRL-C.
+    pub fn on_interrupt(mut self, interrupt: Interrupt) -> Self {
+        self.interrupt = interrupt;
+        self
+    }
+
+    /// Enable the REPL-only commands `help [command]...`, `exit` (or `quit`),
+    /// `history [count] [--clear]`, `clear`, `source <file>`, `set`, `let`,
+    /// `unset`, `alias`, `unalias` and `output [format]`.
+    ///
+    /// Aliases defined with `alias` are persisted next to the history file,
+    /// if there's one.
+    ///
+    /// They are added to the command tree when entering the REPL, and don't
+    /// show up when executing the process args. A subcommand with the same
+    /// name takes precedence over the built-in one.
+    pub fn builtins(mut self, yes: bool) -> Self {
+        self.builtins = yes;
+        self
+    }
+
+    /// Run lines starting with `!` with the system shell, e.g. `!git status`.
+    /// Defaults to false, since it gives access to a shell. Piping into
+    /// external programs is enabled separately, with [`Repl::external_pipes`].
+    ///
+    /// The shell inherits the stdio of the REPL, and CTRL-C only interrupts
+    /// it.
+    pub fn shell_escape(mut self, yes: bool) -> Self {
+        self.shell_escape = yes;
+        self
+    }
+
+    /// Run the commands piped into that aren't known to the REPL as external
+    /// programs, e.g. `list | grep foo`. Defaults to false, in which case they
+    /// are reported as unknown commands.
+    pub fn external_pipes(mut self, yes: bool) -> Self {
+        self.external_pipes = yes;
+        self
+    }
+
+    /// Add the `--script <FILE>` and `--keep-going` args to the root command,
+    /// to execute the lines of a file instead of entering the REPL. They
+    /// can't be given with a subcommand.
+    ///
+    /// Lines are also read as a script when stdin is not a terminal.
+    pub fn script_args(mut self, yes: bool) -> Self {
+        self.script_args = yes;
+        self
+    }
+
+    /// Keep executing a script after a line failed. Defaults to false, which
+    /// stops on the first error.
+    pub fn keep_going(mut self, yes: bool) -> Self {
+        self.keep_going = yes;
+        self
+    }
+
+    /// Source `path` before the first prompt, instead of `~/.<name>rc` where
+    /// `<name>` is the name of the root command.
+    pub fn rc_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.rc_file = Some(path.into());
+        self
+    }
+
+    /// Source the rc file before the first prompt, if it exists. Defaults to
+    /// true.
+    pub fn load_rc(mut self, yes: bool) -> Self {
+        self.load_rc = yes;
+        self
+    }
+
+    /// Set the default format of the values returned by
+    /// [`Command::value_handler`]s. Defaults to text.
+    ///
+    /// With [`Command::with_output_arg`], `--output` in the process args
+    /// overrides it for the whole session. The `output` built-in command
+    /// changes it.
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Run the REPL, and return the context after it ends.
+    ///
+    /// It ends successfully on EOF or an exit command. Otherwise the error
+    /// is a [`ReplError`].
+    pub fn run(self) -> Result<Ctx> {
+        // Changed by `--output` and the `output` command for this REPL only.
+        format::with_default_format(self.output_format, || self.run_impl())
+            .map_err(anyhow::Error::from)
+    }
+
+    fn run_impl(self) -> Result<Ctx, ReplError> {
+        let Self {
+            mut cmd,
+            mut ctx,
+            prompt,
+            continuation_prompt,
+            config,
+            history,
+            banner,
+            exit_commands,
+            interrupt,
+            builtins,
+            shell_escape,
+            external_pipes,
+            script_args,
+            keep_going,
+            rc_file,
+            load_rc,
+            // Set by `run`.
+            output_format: _,
+        } = self;
+
+        // Only the process args take the script args, not the REPL lines.
+        let repl_cmd = cmd.cmd.clone();
+        if script_args {
+            cmd.cmd = cmd
+                .cmd
+                .arg(
+                    Arg::new(SCRIPT_ARG)
+                        .long(SCRIPT_ARG)
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Execute the commands in FILE, one per line"),
+                )
+                .arg(
+                    Arg::new(KEEP_GOING_ARG)
+                        .long(KEEP_GOING_ARG)
+                        .action(ArgAction::SetTrue)
+                        .help("Keep executing the script after a command failed"),
+                );
+        }
+
+        let m = cmd.get_matches();
+        if let Some(subcmd) = m.subcommand_name().filter(|_| script_args) {
+            // Not `args_conflicts_with_subcommands`, which would apply to
+            // all the args of the root.
+            for id in [SCRIPT_ARG, KEEP_GOING_ARG] {
+                if m.value_source(id) == Some(ValueSource::CommandLine) {
+                    let msg = format!(
+                        "the argument '--{}' cannot be used with the subcommand '{}'",
+                        id, subcmd
+                    );
+                    cmd.cmd.error(ErrorKind::ArgumentConflict, msg).exit();
+                }
+            }
+        }
+        format::set_default_format(format::format_of(&m));
+        cmd.exec_with(&m, &mut ctx).map_err(ReplError::Startup)?;
+        if m.subcommand().is_some() {
+            return Ok(ctx);
+        }
+        cmd.cmd = repl_cmd;
+
+        let builtins = if builtins {
+            builtin::inject(&mut cmd.cmd)
+        } else {
+            Vec::new()
+        };
+
+        let session = Session::new(&cmd, ctx, builtins, shell_escape, external_pipes);
+
+        let script = if script_args {
+            m.get_one::<PathBuf>(SCRIPT_ARG)
+        } else {
+            None
+        };
+        if script.is_some() || !std::io::stdin().is_terminal() {
+            let keep_going = keep_going || (script_args && m.get_flag(KEEP_GOING_ARG));
+            // Exit code of the last failed line.
+            let mut code = 1;
+            let mut on_error = |e: anyhow::Error| {
+                code = exit_code(&e);
+                cmd.render_error(&e);
+            };
+            let failed = match script {
+                Some(path) => File::open(path)
+                    .with_context(|| format!("failed to open `{}`", path.display()))
+                    .and_then(|f| {
+                        let source = path.display().to_string();
+                        script::run(
+                            &session,
+                            BufReader::new(f),
+                            &source,
+                            keep_going,
+                            &mut on_error,
+                        )
+                    }),
+                None => script::run(
+                    &session,
+                    std::io::stdin().lock(),
+                    "<stdin>",
+                    keep_going,
+                    &mut on_error,
+                ),
+            }
+            .map_err(ReplError::Script)?;
+            if failed > 0 {
+                let error = UserError::new(format!("{} line(s) failed", failed)).exit_code(code);
+                return Err(ReplError::Script(error.into()));
+            }
+            return Ok(session.into_ctx());
+        }
+
+        let mut editor = Editor::<ReplHelper<Ctx>, DefaultHistory>::with_config(config)?;
+        editor.set_helper(Some(ReplHelper::new(&session)));
+
+        let mut history_path = history
+            .as_ref()
+            .and_then(|h| h.resolve_path(cmd.get_name()));
+        if let Some(history) = &history {
+            history.configure(&mut editor)?;
+            if let Some(path) = &history_path {
+                match history.load(&mut editor, path) {
+                    Ok(()) => {
+                        session.set_history_path(path.clone());
+                        let aliases_path = History::aliases_path(path);
+                        if let Err(e) = session.load_aliases(aliases_path, history.line_filter()) {
+                            cmd.render_error(&e);
+                        }
+                    }
+                    // Keep going without persistence.
+                    Err(e) => {
+                        let msg = format!("failed to load the history from `{}`", path.display());
+                        cmd.render_error(&history_error(e).context(msg));
+                        history_path = None;
+                    }
+                }
+            }
+        }
+        // Without persistence, lines are just added to the in-memory history.
+        let history = history.unwrap_or_default();
+
+        if let Some(banner) = banner {
+            println!("{}", banner);
+        }
+
+        let rc_file = rc_file.or_else(|| dirs::rc_file(cmd.get_name()));
+        if let Some(path) = rc_file.filter(|p| load_rc && p.is_file()) {
+            if let Err(e) = session.source(&path) {
+                cmd.render_error(&e);
+            }
+        }
+
+        loop {
+            let (plain_prompt, styled_prompt) =
+                scoped_prompt(prompt(&session.ctx()), &session.scope());
+            if let Some(helper) = editor.helper_mut() {
+                helper.set_prompt(&plain_prompt, styled_prompt);
+            }
+            match read_input(&mut editor, &plain_prompt, &continuation_prompt) {
+                Ok(line) => {
+                    if let Err(e) = history.add(&mut editor, history_path.as_deref(), &line) {
+                        let mut e = history_error(e);
+                        // Stop persisting it, instead of failing on each line.
+                        if let Some(path) = history_path.take() {
+                            e = e.context(format!(
+                                "failed to write the history to `{}`",
+                                path.display()
+                            ));
+                        }
+                        cmd.render_error(&e);
+                    }
+
+                    if exit_commands.iter().any(|c| c == line.trim()) {
+                        if session.pop_scope() {
+                            continue;
+                        }
+                        break;
+                    }
+
+                    let res = session.exec_line(&line, Some(&mut editor));
+                    // Output that doesn't end with a newline shows before
+                    // the next prompt.
+                    let _ = output().flush();
+                    match res {
+                        Ok(Flow::Continue) => {}
+                        Ok(Flow::Exit) => break,
+                        Err(e) => cmd.render_error(&e),
+                    }
+                }
+                Err(ReadlineError::Eof) => break,
+                Err(ReadlineError::Interrupted) => match &interrupt {
+                    Interrupt::Message(msg) => println!("{}", msg),
+                    Interrupt::Ignore => {}
+                    Interrupt::Exit => break,
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        drop(editor);
+        Ok(session.into_ctx())
+    }
+}
+
+/// Read a line with `prompt`, and the next ones with `continuation` while
+/// it's incomplete.
+///
+/// On EOF the input is returned as is, for its error to be reported.
+fn read_input<Ctx>(
+    editor: &mut Editor<ReplHelper<'_, '_, Ctx>, DefaultHistory>,
+    prompt: &str,
+    continuation: &str,
+) -> rustyline::Result<String> {
+    let mut input = editor.readline(prompt)?;
+    let mut res = Ok(());
+    if let Some(helper) = editor.helper_mut() {
+        helper.set_continuation(true);
+    }
+    while let Some(c) = line::continuation(&input) {
+        match editor.readline(continuation) {
+            Ok(next) => c.join(&mut input, &next),
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        }
+    }
+    if let Some(helper) = editor.helper_mut() {
+        helper.set_continuation(false);
+    }
+    res.map(|_| input)
+}
+
+/// Append the scope to the prompt, e.g. `app> ` becomes `app/db> `.
+///
+/// Returns the plain prompt and the styled one.
+fn scoped_prompt(prompt: StyledStr, scope: &[String]) -> (String, String) {
+    let styled = prompt.ansi().to_string();
+    if scope.is_empty() {
+        return (prompt.to_string(), styled);
+    }
+
+    // Drop the trailing `> `, but not the escape codes around it, e.g. a
+    // reset, so that both prompts keep the same text.
+    let mut tokens = ansi_tokens(&styled);
+    let mut i = tokens.len();
+    while i > 0 {
+        i -= 1;
+        match tokens[i] {
+            (_, true) => {}
+            (text, false) if text.trim().is_empty() => {
+                tokens.remove(i);
+            }
+            (">", false) => {
+                tokens.remove(i);
+                break;
+            }
+            _ => break,
+        }
+    }
+    let suffix = format!("/{}> ", scope.join("/"));
+    let plain: String = tokens
+        .iter()
+        .filter(|(_, esc)| !esc)
+        .map(|(t, _)| *t)
+        .collect();
+    let styled: String = tokens.iter().map(|(t, _)| *t).collect();
+    (plain + &suffix, styled + &suffix)
+}
+
+/// Split `s` into its chars and ANSI escape sequences, which are flagged.
+fn ansi_tokens(s: &str) -> Vec<(&str, bool)> {
+    let mut tokens = Vec::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        let len = match rest.strip_prefix("\x1b[") {
+            // A CSI sequence ends with a byte in `@`..=`~`.
+            Some(csi) => csi
+                .bytes()
+                .position(|b| (0x40..=0x7e).contains(&b))
+                .map_or(rest.len(), |end| end + 3),
+            None if c == '\x1b' => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+            None => c.len_utf8(),
+        };
+        tokens.push((&rest[..len], c == '\x1b'));
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// Convert an error of the history file, without repeating the I/O error
+/// as its cause.
+fn history_error(e: ReadlineError) -> anyhow::Error {
+    match e {
+        ReadlineError::Io(e) => e.into(),
+        e => e.into(),
+    }
+}
+
+/// Shorthand for running a [`Repl`] with `prompt`.
+///
+/// See [`Repl::run`] for the errors.
+pub fn repl<'ctx, Ctx>(cmd: Command<'ctx, Ctx>, ctx: Ctx, prompt: &str) -> Result<()> {
+    Repl::new(cmd, ctx)
+        .prompt(prompt.to_owned())
+        .run()
+        .map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn scoped_prompts() {
+        let prompt = || StyledStr::from("app> ");
+        assert_eq!(
+            scoped_prompt(prompt(), &[]),
+            ("app> ".to_owned(), "app> ".to_owned())
+        );
+        assert_eq!(
+            scoped_prompt(prompt(), &scope(&["db", "table"])),
+            ("app/db/table> ".to_owned(), "app/db/table> ".to_owned())
+        );
+
+        // The escape codes are kept in the styled prompt only, also when
+        // they wrap the `> `.
+        let styled = StyledStr::from("\x1b[32mapp\x1b[0m> ");
+        assert_eq!(
+            scoped_prompt(styled, &scope(&["db"])),
+            ("app/db> ".to_owned(), "\x1b[32mapp\x1b[0m/db> ".to_owned())
+        );
+        let styled = StyledStr::from("\x1b[1mapp> \x1b[0m");
+        assert_eq!(
+            scoped_prompt(styled, &scope(&["db"])),
+            ("app/db> ".to_owned(), "\x1b[1mapp\x1b[0m/db> ".to_owned())
+        );
+
+        // A prompt without a trailing `>` keeps all of its text.
+        assert_eq!(
+            scoped_prompt(StyledStr::from("$ "), &scope(&["db"])),
+            ("$/db> ".to_owned(), "$/db> ".to_owned())
+        );
+    }
+
+    #[test]
+    fn split_ansi_tokens() {
+        assert_eq!(ansi_tokens(""), []);
+        assert_eq!(
+            ansi_tokens("a\x1b[1;32mé\x1b[0m"),
+            [
+                ("a", false),
+                ("\x1b[1;32m", true),
+                ("é", false),
+                ("\x1b[0m", true)
+            ]
+        );
+        // Other escapes are two chars, and an unterminated one runs to the
+        // end.
+        assert_eq!(
+            ansi_tokens("\x1b7a\x1b[12"),
+            [("\x1b7", true), ("a", false), ("\x1b[12", true)]
+        );
+    }
+}
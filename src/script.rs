@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, Write};
+
+use crate::builtin::Flow;
+use crate::io::output;
+use crate::line;
+use crate::session::Session;
+
+/// Execute the lines from `reader`, where `source` names it in errors.
+///
+/// Lines with an unterminated quote or ending with `\` are joined with the
+/// next ones. Blank lines and lines starting with `#` are skipped. Errors are
+/// passed to `on_error`, and unless `keep_going` is set the first one stops
+/// the script. Returns the number of failed lines.
+pub(crate) fn run<Ctx>(
+    session: &Session<'_, '_, Ctx>,
+    reader: impl BufRead,
+    source: &str,
+    keep_going: bool,
+    on_error: &mut dyn FnMut(anyhow::Error),
+) -> Result<usize> {
+    let mut failed = 0;
+    let mut lines = reader.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let read_error = || format!("failed to read `{}`", source);
+        let mut line = line.with_context(read_error)?;
+        while let Some(continuation) = line::continuation(&line) {
+            match lines.next() {
+                Some((_, next)) => continuation.join(&mut line, &next.with_context(read_error)?),
+                None => break,
+            }
+        }
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let res = session.exec_line(&line, None);
+        // Before the error, if any.
+        let _ = output().flush();
+        match res {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Exit) => break,
+            Err(e) => {
+                on_error(e.context(format!("{}:{}: `{}`", source, i + 1, trimmed)));
+                failed += 1;
+                if !keep_going {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(failed)
+}
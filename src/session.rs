@@ -0,0 +1,692 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::error::ErrorKind;
+use clap::ArgMatches;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::builtin::{self, Flow, LineEditor};
+use crate::error::{self, UserError};
+use crate::history::FilterFn;
+use crate::io;
+use crate::line::{self, Join};
+use crate::process;
+use crate::script;
+use crate::suggest;
+use crate::Command;
+
+/// Max nesting of `source` commands, e.g. for files sourcing themselves.
+const MAX_SOURCE_DEPTH: usize = 16;
+
+/// State of a running REPL, shared by the loop and the editor's helper.
+pub(crate) struct Session<'a, 'ctx, Ctx> {
+    root: &'a Command<'ctx, Ctx>,
+    ctx: RefCell<Ctx>,
+    builtins: Vec<clap::Command>,
+    shell_escape: bool,
+    external_pipes: bool,
+    // Names of the subcommands entered with `Command::repl_scope`.
+    scope: RefCell<Vec<String>>,
+    // Number of nested `source` commands being executed.
+    source_depth: Cell<usize>,
+    // Variables set with `set` and `let`.
+    vars: RefCell<BTreeMap<String, String>>,
+    // Exit status of the last command, `$?`.
+    status: Cell<i32>,
+    aliases: RefCell<BTreeMap<String, String>>,
+    // Where the aliases are persisted, if they are.
+    aliases_path: RefCell<Option<PathBuf>>,
+    // The filter of the history, the aliases it rejects are not persisted.
+    aliases_filter: RefCell<Option<Rc<FilterFn>>>,
+    // The history file, if the history is persisted.
+    history_path: RefCell<Option<PathBuf>>,
+    // Aliases being executed and their args, which are the positional
+    // parameters of the last one.
+    alias_stack: RefCell<Vec<(String, Vec<String>)>>,
+}
+
+impl<'a, 'ctx, Ctx> Session<'a, 'ctx, Ctx> {
+    pub(crate) fn new(
+        root: &'a Command<'ctx, Ctx>,
+        ctx: Ctx,
+        builtins: Vec<clap::Command>,
+        shell_escape: bool,
+        external_pipes: bool,
+    ) -> Self {
+        Self {
+            root,
+            ctx: RefCell::new(ctx),
+            builtins,
+            shell_escape,
+            external_pipes,
+            scope: RefCell::new(Vec::new()),
+            source_depth: Cell::new(0),
+            vars: RefCell::new(BTreeMap::new()),
+            status: Cell::new(0),
+            aliases: RefCell::new(BTreeMap::new()),
+            aliases_path: RefCell::new(None),
+            aliases_filter: RefCell::new(None),
+            history_path: RefCell::new(None),
+            alias_stack: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn root(&self) -> &'a Command<'ctx, Ctx> {
+        self.root
+    }
+
+    pub(crate) fn ctx(&self) -> Ref<'_, Ctx> {
+        self.ctx.borrow()
+    }
+
+    pub(crate) fn into_ctx(self) -> Ctx {
+        self.ctx.into_inner()
+    }
+
+    pub(crate) fn builtins(&self) -> &[clap::Command] {
+        &self.builtins
+    }
+
+    pub(crate) fn scope(&self) -> Ref<'_, Vec<String>> {
+        self.scope.borrow()
+    }
+
+    /// The command that lines are currently dispatched to.
+    pub(crate) fn scope_cmd(&self) -> &'a Command<'ctx, Ctx> {
+        self.scope
+            .borrow()
+            .iter()
+            .fold(self.root, |cmd, name| &cmd.subcmds[name])
+    }
+
+    /// The commands above the current scope, from the root.
+    fn scope_parents(&self) -> Vec<&'a Command<'ctx, Ctx>> {
+        let mut cmd = self.root;
+        let mut parents = Vec::new();
+        for name in self.scope.borrow().iter() {
+            parents.push(cmd);
+            cmd = &cmd.subcmds[name];
+        }
+        parents
+    }
+
+    /// Leave the current scope, return false if it's already the root.
+    pub(crate) fn pop_scope(&self) -> bool {
+        self.scope.borrow_mut().pop().is_some()
+    }
+
+    pub(crate) fn vars(&self) -> Ref<'_, BTreeMap<String, String>> {
+        self.vars.borrow()
+    }
+
+    pub(crate) fn set_var(&self, name: &str, value: String) -> Result<()> {
+        if !line::is_var_name(name) {
+            bail!("invalid variable name: `{}`", name);
+        }
+        self.vars.borrow_mut().insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    pub(crate) fn unset_var(&self, name: &str) {
+        self.vars.borrow_mut().remove(name);
+    }
+
+    /// Value of the variable `name`, falling back to the environment.
+    ///
+    /// Inside an alias, `$@` is all of its args and `$1`, `$2`... each of
+    /// them.
+    fn var(&self, name: &str) -> Result<Vec<String>> {
+        if name == "?" {
+            return Ok(vec![self.status.get().to_string()]);
+        }
+        if let Some((_, params)) = self.alias_stack.borrow().last() {
+            if name == "@" {
+                return Ok(params.clone());
+            }
+            if let Some(param) = name.parse::<usize>().ok().filter(|&n| n > 0) {
+                return params
+                    .get(param - 1)
+                    .map(|p| vec![p.clone()])
+                    .ok_or_else(|| anyhow!("missing argument: `${}`", param));
+            }
+        }
+        if let Some(value) = self.vars.borrow().get(name) {
+            return Ok(vec![value.clone()]);
+        }
+        std::env::var(name)
+            .map(|value| vec![value])
+            .map_err(|_| anyhow!("undefined variable: `{}`", name))
+    }
+
+    pub(crate) fn aliases(&self) -> Ref<'_, BTreeMap<String, String>> {
+        self.aliases.borrow()
+    }
+
+    pub(crate) fn set_alias(&self, name: &str, value: String) -> Result<()> {
+        check_alias_name(name)?;
+        self.aliases.borrow_mut().insert(name.to_owned(), value);
+        self.save_aliases()
+    }
+
+    pub(crate) fn unset_alias(&self, name: &str) -> Result<()> {
+        if self.aliases.borrow_mut().remove(name).is_none() {
+            bail!("no such alias: `{}`", name);
+        }
+        self.save_aliases()
+    }
+
+    /// Load the aliases from `path`, and save them there when they change.
+    ///
+    /// On error, none is loaded and they are not saved, to keep the file.
+    pub(crate) fn load_aliases(&self, path: PathBuf, filter: Option<Rc<FilterFn>>) -> Result<()> {
+        let aliases = match std::fs::read_to_string(&path) {
+            Ok(aliases) => aliases,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read `{}`", path.display()))
+            }
+        };
+
+        let mut map = BTreeMap::new();
+        let mut lines = aliases.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            // Values are quoted, and may span several lines.
+            let mut entry = line.to_owned();
+            while let Some(continuation) = line::continuation(&entry) {
+                match lines.next() {
+                    Some((_, next)) => continuation.join(&mut entry, next),
+                    None => break,
+                }
+            }
+            let parsed =
+                entry.split_once('=').and_then(|(name, value)| {
+                    match shell_words::split(value).ok()?.as_slice() {
+                        [value] => Some((name, value.clone())),
+                        _ => None,
+                    }
+                });
+            let invalid = || format!("{}:{}: invalid alias", path.display(), i + 1);
+            let (name, value) = parsed.with_context(invalid)?;
+            check_alias_name(name).with_context(invalid)?;
+            map.insert(name.to_owned(), value);
+        }
+        self.aliases.borrow_mut().extend(map);
+        *self.aliases_path.borrow_mut() = Some(path);
+        *self.aliases_filter.borrow_mut() = filter;
+        Ok(())
+    }
+
+    fn save_aliases(&self) -> Result<()> {
+        let Some(path) = &*self.aliases_path.borrow() else {
+            return Ok(());
+        };
+        let filter = self.aliases_filter.borrow();
+        let aliases: String = self
+            .aliases
+            .borrow()
+            .iter()
+            .filter(|(_, value)| filter.as_ref().is_none_or(|f| f(value)))
+            .map(|(name, value)| format!("{}={}\n", name, shell_words::quote(value)))
+            .collect();
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // Only readable by the user, like the history file.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| file.write_all(aliases.as_bytes()))
+            .with_context(|| format!("failed to write `{}`", path.display()))
+    }
+
+    pub(crate) fn history_path(&self) -> Ref<'_, Option<PathBuf>> {
+        self.history_path.borrow()
+    }
+
+    pub(crate) fn set_history_path(&self, path: PathBuf) {
+        *self.history_path.borrow_mut() = Some(path);
+    }
+
+    /// The alias named `name`, unless it's already being executed.
+    fn find_alias(&self, name: &str) -> Option<String> {
+        if self.alias_stack.borrow().iter().any(|(n, _)| n == name) {
+            return None;
+        }
+        self.aliases.borrow().get(name).cloned()
+    }
+
+    /// Execute the alias `name` with `args`.
+    ///
+    /// Its args are appended to it, unless it refers to them with `$1`, `$@`
+    /// and so on.
+    fn exec_alias(
+        &self,
+        name: &str,
+        alias: &str,
+        args: &[String],
+        editor: Option<&mut dyn LineEditor>,
+    ) -> Result<Flow> {
+        let mut line = alias.to_owned();
+        if !line::has_params(alias) {
+            for arg in args {
+                line.push(' ');
+                line.push_str(&shell_words::quote(arg));
+            }
+        }
+        self.alias_stack
+            .borrow_mut()
+            .push((name.to_owned(), args.to_vec()));
+        let res = self.exec_line(&line, editor);
+        self.alias_stack.borrow_mut().pop();
+        res
+    }
+
+    /// Expand the variables in `text` and split it into words.
+    fn expand(&self, text: &str) -> Result<Vec<String>> {
+        split(&line::expand(text, &|name| self.var(name))?)
+    }
+
+    /// The shell command of `line`, if it's a shell escape and they are
+    /// enabled.
+    pub(crate) fn shell_escape<'l>(&self, line: &'l str) -> Option<&'l str> {
+        line.trim_start()
+            .strip_prefix('!')
+            .filter(|_| self.shell_escape)
+    }
+
+    /// Whether unknown commands piped into are run as external programs.
+    pub(crate) fn external_pipes(&self) -> bool {
+        self.external_pipes
+    }
+
+    /// Execute a line of input.
+    ///
+    /// The line may chain commands with `;`, `&&` and `||`, and pipe them
+    /// with `|`. Variables are expanded right before each command runs.
+    /// Errors of the commands followed by another one are rendered, the last
+    /// one is returned.
+    ///
+    /// A line starting with `!` is run with the system shell instead, if
+    /// shell escapes are enabled.
+    ///
+    /// `editor` is `None` when running a script.
+    pub(crate) fn exec_line(
+        &self,
+        line: &str,
+        mut editor: Option<&mut dyn LineEditor>,
+    ) -> Result<Flow> {
+        if let Some(cmd) = self.shell_escape(line) {
+            let res = process::shell(cmd);
+            self.status
+                .set(res.as_ref().map_or_else(error::exit_code, |_| 0));
+            return res.map(|_| Flow::Continue);
+        }
+
+        let pipelines = parse(line).inspect_err(|e| self.status.set(error::exit_code(e)))?;
+
+        let mut last = Ok(());
+        for pipeline in pipelines {
+            let runs = match pipeline.join {
+                Join::Always => true,
+                Join::IfOk => last.is_ok(),
+                Join::IfErr => last.is_err(),
+                Join::Pipe => unreachable!("piped commands are in the same pipeline"),
+            };
+            if !runs {
+                continue;
+            }
+            if let Err(e) = std::mem::replace(&mut last, Ok(())) {
+                self.root.render_error(&e);
+            }
+            let editor = editor.as_mut().map(|e| &mut **e as _);
+            let res = match pipeline.redirect {
+                Some((target, append)) => {
+                    self.expand(target)
+                        .and_then(|target| match target.as_slice() {
+                            [target] => self.exec_redirected(Path::new(target), append, || {
+                                self.exec_pipeline(&pipeline.cmds, editor)
+                            }),
+                            // Expanded to no word or to several ones.
+                            _ => Err(syntax_error(if append { ">>" } else { ">" })),
+                        })
+                }
+                None => self.exec_pipeline(&pipeline.cmds, editor),
+            };
+            self.status
+                .set(res.as_ref().map_or_else(error::exit_code, |_| 0));
+            match res {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Exit) => return Ok(Flow::Exit),
+                Err(e) => last = Err(e),
+            }
+        }
+        last.map(|_| Flow::Continue)
+    }
+
+    /// Run `f` with its output written to the file at `path`.
+    fn exec_redirected(
+        &self,
+        path: &Path,
+        append: bool,
+        f: impl FnOnce() -> Result<Flow>,
+    ) -> Result<Flow> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .with_context(|| format!("failed to open `{}`", path.display()))?;
+        let (res, flushed) = io::redirect_output(Box::new(BufWriter::new(file)), f);
+        let flow = res?;
+        flushed.with_context(|| format!("failed to write `{}`", path.display()))?;
+        Ok(flow)
+    }
+
+    /// Execute commands with the output of each one as the input of the
+    /// next, stopping on the first error.
+    ///
+    /// Commands after the first one that aren't known here are run as
+    /// external programs if that's enabled.
+    fn exec_pipeline(
+        &self,
+        cmds: &[&str],
+        mut editor: Option<&mut dyn LineEditor>,
+    ) -> Result<Flow> {
+        let mut input = None;
+        let mut flow = Flow::Continue;
+        for (i, cmd) in cmds.iter().enumerate() {
+            let args = &self.expand(cmd)?;
+            if args.is_empty() && cmds.len() > 1 {
+                return Err(syntax_error("|"));
+            }
+            let editor = editor.as_mut().map(|e| &mut **e as _);
+            let run = || match input.take() {
+                None => self.exec_args(args, editor),
+                Some(input) if !self.external_pipes || self.is_command(&args[0]) => {
+                    io::redirect_input(input, || self.exec_args(args, editor))
+                }
+                Some(input) => process::run(args, Some(input)).map(|_| Flow::Continue),
+            };
+            if i + 1 == cmds.len() {
+                flow = run()?;
+            } else {
+                let (res, output) = io::capture_output(run);
+                res?;
+                input = Some(output);
+            }
+        }
+        Ok(flow)
+    }
+
+    /// Execute a single command.
+    fn exec_args(&self, args: &[String], editor: Option<&mut dyn LineEditor>) -> Result<Flow> {
+        let Some(first) = args.first() else {
+            return Ok(Flow::Continue);
+        };
+        if let Some(alias) = self.find_alias(first) {
+            return self.exec_alias(first, &alias, &args[1..], editor);
+        }
+
+        let cmd = self.scope_cmd();
+        if first == ".." && args.len() == 1 && self.pop_scope() {
+            return Ok(Flow::Continue);
+        }
+
+        if cmd.find_subcmd(first).is_none() {
+            if let Some(b) = builtin::find(&self.builtins, first) {
+                let flow = builtin::run(self, b, args, editor)?;
+                if let Flow::Exit = flow {
+                    if self.pop_scope() {
+                        return Ok(Flow::Continue);
+                    }
+                }
+                return Ok(flow);
+            }
+        }
+
+        if let Some(path) = self.find_scope(cmd, args) {
+            self.scope.borrow_mut().extend(path);
+            return Ok(Flow::Continue);
+        }
+
+        let aliases = self.aliases.borrow();
+        let others = self
+            .builtins
+            .iter()
+            .map(|b| b.get_name())
+            .chain(aliases.keys().map(String::as_str));
+        if let Some(e) = suggest::unknown_command(cmd, args, others) {
+            return Err(e);
+        }
+        drop(aliases);
+
+        // Without the binary name, usages are relative to the scope.
+        let clap_cmd = cmd.cmd.clone().no_binary_name(true);
+        if let Some(m) = try_matches(clap_cmd, args)? {
+            let ctx = &mut self.ctx.borrow_mut();
+            match &self.scope_parents()[..] {
+                [] => cmd.exec_with(&m, ctx)?,
+                parents => cmd.dispatch_under(parents, &m, ctx)?,
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Execute the lines of the file at `path`, stopping on the first error.
+    pub(crate) fn source(&self, path: &Path) -> Result<()> {
+        let depth = self.source_depth.get();
+        if depth >= MAX_SOURCE_DEPTH {
+            bail!(
+                "`source` nested too deeply, max depth is {}",
+                MAX_SOURCE_DEPTH
+            );
+        }
+        let file =
+            File::open(path).with_context(|| format!("failed to open `{}`", path.display()))?;
+
+        self.source_depth.set(depth + 1);
+        let mut error = None;
+        let res = script::run(
+            self,
+            BufReader::new(file),
+            &path.display().to_string(),
+            false,
+            &mut |e| error = Some(e),
+        );
+        self.source_depth.set(depth);
+
+        res?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether `name` is a command in the current scope.
+    fn is_command(&self, name: &str) -> bool {
+        name == ".."
+            || self.find_alias(name).is_some()
+            || self.scope_cmd().find_subcmd(name).is_some()
+            || builtin::find(&self.builtins, name).is_some()
+    }
+
+    /// If `args` are only the names of subcommands, ending with one marked
+    /// with `Command::repl_scope`, return their names.
+    fn find_scope(&self, cmd: &Command<'ctx, Ctx>, args: &[String]) -> Option<Vec<String>> {
+        let mut cmd = cmd;
+        let mut path = Vec::new();
+        for arg in args {
+            cmd = cmd.find_subcmd(arg)?;
+            path.push(cmd.get_name().to_owned());
+        }
+        cmd.repl_scope.then_some(path)
+    }
+}
+
+/// Parse `args` with `cmd`, or print the help or version they ask for and
+/// return `None`.
+pub(crate) fn try_matches(cmd: clap::Command, args: &[String]) -> Result<Option<ArgMatches>> {
+    let color = cmd.get_color();
+    match cmd.try_get_matches_from(args) {
+        Ok(m) => Ok(Some(m)),
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => {
+            io::print_styled(&e.render(), color)?;
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Parse `line` into pipelines chained with `;`, `&&` and `||`.
+fn parse(line: &str) -> Result<Vec<Pipeline<'_>>> {
+    let mut pipelines: Vec<Pipeline> = Vec::new();
+    let mut prev_empty = true;
+    for segment in line::split_commands(line) {
+        let args = split(segment.text)?;
+        if segment.join != Join::Always && (prev_empty || args.is_empty()) {
+            return Err(syntax_error(segment.join.as_str()));
+        }
+        prev_empty = args.is_empty();
+
+        let redirect = match segment.redirect {
+            Some(r) => {
+                let op = if r.append { ">>" } else { ">" };
+                if args.is_empty() || split(r.target)?.len() != 1 {
+                    return Err(syntax_error(op));
+                }
+                Some((r.target, r.append))
+            }
+            None => None,
+        };
+        match pipelines.last_mut() {
+            Some(pipeline) if segment.join == Join::Pipe => {
+                // Only the last command of a pipeline can be redirected.
+                if pipeline.redirect.is_some() {
+                    return Err(syntax_error("|"));
+                }
+                pipeline.cmds.push(segment.text);
+                pipeline.redirect = redirect;
+            }
+            _ if !args.is_empty() => pipelines.push(Pipeline {
+                join: segment.join,
+                cmds: vec![segment.text],
+                redirect,
+            }),
+            _ => {}
+        }
+    }
+    Ok(pipelines)
+}
+
+fn check_alias_name(name: &str) -> Result<()> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if name.is_empty() || !valid {
+        bail!("invalid alias name: `{}`", name);
+    }
+    Ok(())
+}
+
+fn syntax_error(near: &str) -> anyhow::Error {
+    UserError::new(format!("syntax error near `{}`", near))
+        .exit_code(2)
+        .into()
+}
+
+/// Commands connected with `|`, not expanded yet.
+struct Pipeline<'a> {
+    join: Join,
+    cmds: Vec<&'a str>,
+    redirect: Option<(&'a str, bool)>,
+}
+
+fn split(s: &str) -> Result<Vec<String>> {
+    shell_words::split(s).map_err(|e| {
+        UserError::new(format!("parse error: `{}`", e))
+            .exit_code(2)
+            .into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A pipeline, with its trimmed commands and redirect.
+    type Parsed<'a> = (Join, Vec<&'a str>, Option<(&'a str, bool)>);
+
+    /// The pipelines of `line`.
+    fn pipelines(line: &str) -> Vec<Parsed<'_>> {
+        parse(line)
+            .unwrap()
+            .into_iter()
+            .map(|p| {
+                let cmds = p.cmds.iter().map(|c| c.trim()).collect();
+                (p.join, cmds, p.redirect.map(|(t, a)| (t.trim(), a)))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_chains() {
+        assert_eq!(
+            pipelines("a; b && c || d"),
+            [
+                (Join::Always, vec!["a"], None),
+                (Join::Always, vec!["b"], None),
+                (Join::IfOk, vec!["c"], None),
+                (Join::IfErr, vec!["d"], None),
+            ]
+        );
+        // Empty commands are skipped after `;`.
+        assert_eq!(
+            pipelines("; a;; b;"),
+            [
+                (Join::Always, vec!["a"], None),
+                (Join::Always, vec!["b"], None)
+            ]
+        );
+        assert_eq!(pipelines("# a && b"), []);
+        assert_eq!(
+            pipelines("a | b > out && c"),
+            [
+                (Join::Always, vec!["a", "b"], Some(("out", false))),
+                (Join::IfOk, vec!["c"], None),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_syntax_errors() {
+        let cases = [
+            ("a &&", "&&"),
+            ("&& b", "&&"),
+            ("a || ; b", "||"),
+            ("a; || b", "||"),
+            ("a | ", "|"),
+            ("a > out | b", "|"),
+            ("a >", ">"),
+            ("a >> x y", ">>"),
+            ("> out", ">"),
+        ];
+        for (line, near) in cases {
+            let e = parse(line)
+                .err()
+                .unwrap_or_else(|| panic!("{:?} parsed", line));
+            assert_eq!(
+                e.to_string(),
+                format!("syntax error near `{}`", near),
+                "{:?}",
+                line
+            );
+            assert_eq!(error::exit_code(&e), 2);
+        }
+        assert!(parse("a 'b").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::Error;
+
+use crate::complete::{Walker, WordKind};
+use crate::{Command, UserError};
+
+/// Min similarity of a suggestion, the same as clap's.
+const THRESHOLD: f64 = 0.7;
+const MAX_SUGGESTIONS: usize = 3;
+
+/// If `args` start with an unknown subcommand of `cmd`, return an error
+/// suggesting the closest commands.
+///
+/// Suggestions are the paths of subcommands in the tree, e.g. `db table`,
+/// with their aliases, and the `extra` top-level commands.
+pub(crate) fn unknown_command<'a, Ctx>(
+    cmd: &Command<'_, Ctx>,
+    args: &[String],
+    extra: impl IntoIterator<Item = &'a str>,
+) -> Option<Error> {
+    let mut walker = Walker::new(&cmd.cmd, cmd);
+    let mut words = Vec::new();
+    let mut rest = None;
+    for (i, arg) in args.iter().enumerate() {
+        let expects_subcmd = walker.cmd.has_subcommands();
+        match walker.step(arg) {
+            WordKind::Subcommand => words.push(arg.as_str()),
+            WordKind::Unknown if expects_subcmd => {
+                words.push(arg);
+                rest = Some(&args[i + 1..]);
+                break;
+            }
+            WordKind::Flag | WordKind::Value => {}
+            _ => return None,
+        }
+    }
+    let unknown = words.len();
+    // Following words may be part of the path too, e.g. `tabel list`.
+    words.extend(
+        rest?
+            .iter()
+            .filter(|a| !a.starts_with('-'))
+            .map(String::as_str),
+    );
+
+    let mut paths = Vec::new();
+    collect_paths(&cmd.cmd, &mut Vec::new(), &mut paths);
+    paths.extend(extra.into_iter().map(|name| vec![name.to_owned()]));
+
+    let mut suggestions: Vec<(f64, String)> = paths
+        .into_iter()
+        // Paths of the known subcommands would be perfect matches.
+        .filter(|path| (unknown..=words.len()).contains(&path.len()))
+        .map(|path| {
+            let path = path.join(" ");
+            let typed = words[..path.split(' ').count()].join(" ");
+            (strsim::jaro_winkler(&typed, &path), path)
+        })
+        .filter(|(score, _)| *score > THRESHOLD)
+        .collect();
+    suggestions.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    suggestions.dedup_by(|a, b| a.1 == b.1);
+
+    let typed = words[..unknown].join(" ");
+    let suggestions: Vec<String> = suggestions
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, path)| format!("`{}`", path))
+        .collect();
+    Some(
+        match suggestions.len() {
+            0 => UserError::new(format!("unknown command: `{}`", typed)),
+            1 => UserError::new(format!(
+                "unknown command: `{}`, did you mean {}?",
+                typed, suggestions[0]
+            )),
+            _ => UserError::new(format!(
+                "unknown command: `{}`, did you mean one of {}?",
+                typed,
+                suggestions.join(", ")
+            )),
+        }
+        .into(),
+    )
+}
+
+/// Collect the paths of the visible subcommands of `cmd`, once with each of
+/// their names.
+fn collect_paths(cmd: &clap::Command, prefix: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+    for subcmd in cmd.get_subcommands().filter(|c| !c.is_hide_set()) {
+        for name in std::iter::once(subcmd.get_name()).chain(subcmd.get_all_aliases()) {
+            prefix.push(name.to_owned());
+            paths.push(prefix.clone());
+            collect_paths(subcmd, prefix, paths);
+            prefix.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> Command<'static, ()> {
+        let mut hidden = Command::new("pushd");
+        hidden.cmd = hidden.cmd.hide(true);
+        Command::new("app")
+            .subcommand(Command::new("push").arg(clap::Arg::new("remote")))
+            .subcommand(Command::new("pull"))
+            .subcommand(Command::new("put"))
+            .subcommand(hidden)
+            .subcommand(
+                Command::new("db")
+                    .alias("database")
+                    .subcommand(Command::new("table").subcommand(Command::new("list"))),
+            )
+    }
+
+    fn unknown(args: &str) -> Option<String> {
+        let args: Vec<String> = args.split(' ').map(str::to_owned).collect();
+        unknown_command(&app(), &args, ["alias"]).map(|e| e.to_string())
+    }
+
+    #[test]
+    fn suggest_closest() {
+        let cases = [
+            // The closest first, and at most `MAX_SUGGESTIONS`.
+            ("pus", "did you mean one of `push`, `put`, `pull`?"),
+            ("pu --force", "did you mean one of `put`, `pull`, `push`?"),
+            // Hidden commands are not suggested.
+            ("pushh", "did you mean `push`?"),
+            ("databse", "did you mean `database`?"),
+            ("alais", "did you mean `alias`?"),
+        ];
+        for (args, suggestion) in cases {
+            let typed = args.split(' ').next().unwrap();
+            let expected = format!("unknown command: `{}`, {}", typed, suggestion);
+            assert_eq!(unknown(args).as_deref(), Some(&*expected));
+        }
+        assert_eq!(unknown("zzz").as_deref(), Some("unknown command: `zzz`"));
+    }
+
+    #[test]
+    fn suggest_paths() {
+        // The words after the unknown one may be part of the path.
+        assert_eq!(
+            unknown("db tabel list").as_deref(),
+            Some(
+                "unknown command: `db tabel`, did you mean one of `db table list`, \
+                 `db table`, `database table list`?"
+            )
+        );
+        assert_eq!(
+            unknown("db tabel").as_deref(),
+            Some("unknown command: `db tabel`, did you mean one of `db table`, `database table`?")
+        );
+        // Known commands, and args of known commands.
+        assert_eq!(unknown("db table list"), None);
+        assert_eq!(unknown("push x"), None);
+    }
+}